@@ -53,6 +53,27 @@ enum DecoderType {
     Deflate,
 }
 
+impl DecoderType {
+    /// The decoder for a `Content-Encoding` or `Transfer-Encoding` value, if it is supported.
+    fn from_coding(coding: &HeaderValue) -> Option<DecoderType> {
+        if coding == HeaderValue::from_static("gzip") {
+            Some(DecoderType::Gzip)
+        } else if coding == HeaderValue::from_static("br") {
+            Some(DecoderType::Brotli)
+        } else if coding == HeaderValue::from_static("deflate") {
+            Some(DecoderType::Deflate)
+        } else {
+            None
+        }
+    }
+}
+
+/// Whether a response body in the given coding is decoded before it reaches the rest
+/// of the fetch pipeline, such as the HTTP cache.
+pub(crate) fn is_decoded_coding(coding: &HeaderValue) -> bool {
+    DecoderType::from_coding(coding).is_some()
+}
+
 enum Inner {
     /// A `PlainText` decoder just returns the response content as is.
     PlainText(BodyStream),
@@ -124,17 +145,7 @@ impl Decoder {
             .iter()
             .chain(response.headers().get_all(TRANSFER_ENCODING).iter());
         let decoder = values.fold(None, |acc, enc| {
-            acc.or_else(|| {
-                if enc == HeaderValue::from_static("gzip") {
-                    Some(DecoderType::Gzip)
-                } else if enc == HeaderValue::from_static("br") {
-                    Some(DecoderType::Brotli)
-                } else if enc == HeaderValue::from_static("deflate") {
-                    Some(DecoderType::Deflate)
-                } else {
-                    None
-                }
-            })
+            acc.or_else(|| DecoderType::from_coding(enc))
         });
         let content_length = response.headers().typed_get::<ContentLength>();
         match decoder {
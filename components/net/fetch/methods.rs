@@ -620,7 +620,7 @@ pub async fn main_fetch(
     // Step 24.
     target.process_response_eof(request, &response);
 
-    if let Ok(http_cache) = context.state.http_cache.write() {
+    if let Ok(mut http_cache) = context.state.http_cache.write() {
         http_cache.update_awaiting_consumers(request, &response);
     }
 
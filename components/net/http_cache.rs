@@ -7,29 +7,38 @@
 //! A memory cache implementing the logic specified in <http://tools.ietf.org/html/rfc7234>
 //! and <http://tools.ietf.org/html/rfc7232>.
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::Read;
 use std::ops::Bound;
-use std::sync::Mutex;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
 use std::time::{Duration, Instant, SystemTime};
 
+use flate2::Compression;
+use flate2::read::{DeflateDecoder, DeflateEncoder, GzDecoder, ZlibDecoder};
 use headers::{
-    CacheControl, ContentRange, Expires, HeaderMapExt, LastModified, Pragma, Range, Vary,
+    AcceptRanges, CacheControl, ContentLength, ContentRange, Date, ETag, Expires, HeaderMapExt,
+    IfModifiedSince, IfNoneMatch, LastModified, Pragma, Range, Vary,
 };
-use http::header::HeaderValue;
+use http::header::{HeaderName, HeaderValue};
 use http::{HeaderMap, Method, StatusCode, header};
-use log::debug;
+use log::{debug, warn};
 use malloc_size_of::{MallocSizeOf, MallocSizeOfOps, MallocUnconditionalSizeOf};
 use malloc_size_of_derive::MallocSizeOf;
+use mime::Mime;
 use net_traits::http_status::HttpStatus;
-use net_traits::request::Request;
+use net_traits::request::{CacheMode, Request};
 use net_traits::response::{HttpsState, Response, ResponseBody};
-use net_traits::{FetchMetadata, Metadata, ResourceFetchTiming};
+use net_traits::{
+    FetchMetadata, FilteredMetadata, Metadata, ResourceFetchTiming, ResourceTimingType,
+};
 use servo_arc::Arc;
 use servo_config::pref;
 use servo_url::ServoUrl;
 use tokio::sync::mpsc::{UnboundedSender as TokioSender, unbounded_channel as unbounded};
 
+use crate::decoder::is_decoded_coding;
 use crate::fetch::methods::{Data, DoneChannel};
 
 /// The key used to differentiate requests in the cache.
@@ -53,13 +62,165 @@ impl CacheKey {
     }
 }
 
+/// A callback invoked with the url and body size of every resource evicted from the cache.
+pub type EvictionCallback = Box<dyn Fn(&ServoUrl, usize) + Send + Sync>;
+
+/// A predicate deciding whether a response to a request may be stored,
+/// on top of the cacheability rules of the cache.
+pub type StoreFilter = Box<dyn Fn(&Request, &Response) -> bool + Send + Sync>;
+
+/// A source of the current time, so that freshness can be computed deterministically.
+pub trait Clock: Send + Sync {
+    /// The current monotonic time.
+    fn now_instant(&self) -> Instant;
+    /// The current wall-clock time.
+    fn now_system(&self) -> SystemTime;
+}
+
+/// A clock reading the time from the operating system.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_instant(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn now_system(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A clock which only advances when told to, for tests.
+/// Clones share the same time.
+#[derive(Clone)]
+pub struct MockClock {
+    now: Arc<Mutex<(Instant, SystemTime)>>,
+}
+
+impl Default for MockClock {
+    fn default() -> MockClock {
+        MockClock {
+            now: Arc::new(Mutex::new((Instant::now(), SystemTime::now()))),
+        }
+    }
+}
+
+impl MockClock {
+    /// Move the time of the clock forward.
+    pub fn advance(&self, duration: Duration) {
+        let mut now = lock_unpoisoned(&self.now);
+        now.0 += duration;
+        now.1 += duration;
+    }
+}
+
+impl Clock for MockClock {
+    fn now_instant(&self) -> Instant {
+        lock_unpoisoned(&self.now).0
+    }
+
+    fn now_system(&self) -> SystemTime {
+        lock_unpoisoned(&self.now).1
+    }
+}
+
+/// Whether the cache is private to a single user, or shared between users.
+/// <https://tools.ietf.org/html/rfc7234#section-1>
+#[derive(Clone, Copy, Debug, Default, MallocSizeOf, PartialEq)]
+pub enum HttpCacheMode {
+    /// A cache dedicated to a single user.
+    #[default]
+    Private,
+    /// A cache storing responses to be reused by more than one user.
+    Shared,
+}
+
+/// The policy used to pick which resource to evict when the cache is over its memory budget.
+#[derive(Clone, Copy, Debug, Default, MallocSizeOf, PartialEq)]
+pub enum EvictionPolicy {
+    /// Evict the least recently used resource first.
+    #[default]
+    Lru,
+    /// Evict the least frequently used resource first, using recency to break ties.
+    Lfu,
+}
+
+/// Bounds forced on the freshness lifetime of stored responses, regardless of their headers,
+/// for example to make tests deterministic, or to work around origins with broken headers.
+#[derive(Clone, Copy, Debug, Default, MallocSizeOf, PartialEq)]
+pub struct CacheControlOverride {
+    /// The minimum freshness lifetime, if any.
+    pub min_freshness: Option<Duration>,
+    /// The maximum freshness lifetime, if any, which wins over the minimum.
+    pub max_freshness: Option<Duration>,
+}
+
+impl CacheControlOverride {
+    /// Clamp a freshness lifetime computed from the headers of a response.
+    fn apply(&self, expires: Duration) -> Duration {
+        let expires = self.min_freshness.map_or(expires, |min| expires.max(min));
+        self.max_freshness.map_or(expires, |max| expires.min(max))
+    }
+}
+
+/// A monotonic clock ordering the accesses to cached resources.
+static ACCESS_TICKS: AtomicU64 = AtomicU64::new(0);
+
+fn next_access_tick() -> u64 {
+    ACCESS_TICKS.fetch_add(1, Ordering::Relaxed)
+}
+
+/// The fetch producing the body of a cached resource, on which awaiting consumers wait.
+#[derive(Clone, Copy, Debug, MallocSizeOf, PartialEq)]
+enum BodyProducer {
+    /// The fetch which stored the resource.
+    Original,
+    /// One of the awaiting consumers, promoted after the original fetch was aborted.
+    /// It restarted a network fetch, and the other consumers stay parked until it completes.
+    PromotedConsumer,
+}
+
+/// The outcome of the fetch producing the body of a cached resource.
+#[derive(Clone, Copy, Debug, MallocSizeOf, PartialEq)]
+enum FetchOutcome {
+    /// The body is still being received.
+    Pending,
+    /// The body was fully received, and may legitimately be empty.
+    Completed,
+    /// The fetch was aborted, or ended in a network error.
+    Failed,
+}
+
 /// A complete cached resource.
+///
+/// Its locks, some of which are shared with in-flight responses and derived resources,
+/// are always acquired in the following order, to prevent deadlocks between concurrent
+/// users of the cache: `metadata.headers`, `request_headers`, `metadata.trailers`,
+/// `awaiting_body`, `producer`, `outcome`, and finally `body`. A lock which isn't
+/// needed can be skipped, but one must never be acquired while a later one is held.
 #[derive(Clone)]
 struct CachedResource {
+    method: Method,
     request_headers: Arc<Mutex<HeaderMap>>,
+    /// The values of the request header fields selected by the `Vary` header,
+    /// derived once when the resource is stored or refreshed.
+    secondary_key: SecondaryKey,
     body: Arc<Mutex<ResponseBody>>,
+    /// Whether the body is stored compressed, to be decompressed before being served.
+    body_compressed: bool,
+    /// A hash of the body as stored, if it was complete when stored and bodies are hashed.
+    body_hash: Option<u64>,
+    /// Whether the body was dropped to reclaim memory, leaving a stub which can only
+    /// provide validators for a conditional request.
+    body_dropped: bool,
     aborted: Arc<AtomicBool>,
     awaiting_body: Arc<Mutex<Vec<TokioSender<Data>>>>,
+    producer: Arc<Mutex<BodyProducer>>,
+    outcome: Arc<Mutex<FetchOutcome>>,
+    revalidation_requested: Arc<AtomicBool>,
+    hit_count: Arc<AtomicU64>,
+    last_access: Arc<AtomicU64>,
     metadata: CachedMetadata,
     location_url: Option<Result<ServoUrl, String>>,
     https_state: HttpsState,
@@ -67,14 +228,103 @@ struct CachedResource {
     url_list: Vec<ServoUrl>,
     expires: Duration,
     last_validated: Instant,
+    /// The time spent waiting on the locks of the resource, shared with derived resources.
+    #[cfg(feature = "lock-contention")]
+    contention: Arc<LockContention>,
+}
+
+/// The contention measured on the locks of a resource.
+#[cfg(feature = "lock-contention")]
+#[derive(Default)]
+struct LockContention {
+    /// How many times a lock was found held by another thread.
+    contended: AtomicU64,
+    /// The total time spent waiting for those locks, in nanoseconds.
+    waited_nanos: AtomicU64,
+}
+
+/// How much the locks of the resources stored under a url were contended,
+/// see `HttpCache::contention_report`.
+#[cfg(feature = "lock-contention")]
+#[derive(Clone, Debug)]
+pub struct ContentionReportEntry {
+    /// The url under which the resources are stored.
+    pub url: ServoUrl,
+    /// How many times a lock was found held by another thread.
+    pub contended: u64,
+    /// The total time spent waiting for those locks.
+    pub waited: Duration,
+}
+
+/// Lock a mutex of the cache, recovering it if a thread panicked while holding it,
+/// as caching is best-effort, and shouldn't take the whole net thread down with it.
+fn lock_unpoisoned<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|poisoned| {
+        warn!("recovering a poisoned lock of the http cache");
+        poisoned.into_inner()
+    })
+}
+
+impl CachedResource {
+    /// Whether a thread panicked while holding one of the locks of the resource,
+    /// which may have been left inconsistent, so that it can't be used anymore.
+    fn is_poisoned(&self) -> bool {
+        self.metadata.headers.is_poisoned() ||
+            self.request_headers.is_poisoned() ||
+            self.metadata.trailers.is_poisoned() ||
+            self.body.is_poisoned()
+    }
+
+    fn lock_headers(&self) -> MutexGuard<'_, HeaderMap> {
+        self.lock(&self.metadata.headers)
+    }
+
+    fn lock_request_headers(&self) -> MutexGuard<'_, HeaderMap> {
+        self.lock(&self.request_headers)
+    }
+
+    fn lock_body(&self) -> MutexGuard<'_, ResponseBody> {
+        self.lock(&self.body)
+    }
+
+    #[cfg(not(feature = "lock-contention"))]
+    fn lock<'a, T>(&self, mutex: &'a Mutex<T>) -> MutexGuard<'a, T> {
+        lock_unpoisoned(mutex)
+    }
+
+    /// Lock one of the mutexes of the resource, measuring the time spent waiting for it
+    /// when it is held by another thread.
+    #[cfg(feature = "lock-contention")]
+    fn lock<'a, T>(&self, mutex: &'a Mutex<T>) -> MutexGuard<'a, T> {
+        if let Ok(guard) = mutex.try_lock() {
+            return guard;
+        }
+        let start = Instant::now();
+        let guard = lock_unpoisoned(mutex);
+        let waited = u64::try_from(start.elapsed().as_nanos()).unwrap_or(u64::MAX);
+        self.contention.contended.fetch_add(1, Ordering::Relaxed);
+        self.contention
+            .waited_nanos
+            .fetch_add(waited, Ordering::Relaxed);
+        guard
+    }
 }
 
 impl MallocSizeOf for CachedResource {
     fn size_of(&self, ops: &mut MallocSizeOfOps) -> usize {
         // TODO: self.request_headers.unconditional_size_of(ops) +
-        self.body.unconditional_size_of(ops) +
+        self.secondary_key.size_of(ops) +
+            self.body.unconditional_size_of(ops) +
+            self.body_compressed.size_of(ops) +
+            self.body_hash.size_of(ops) +
+            self.body_dropped.size_of(ops) +
             self.aborted.unconditional_size_of(ops) +
             self.awaiting_body.unconditional_size_of(ops) +
+            self.producer.unconditional_size_of(ops) +
+            self.outcome.unconditional_size_of(ops) +
+            self.revalidation_requested.unconditional_size_of(ops) +
+            self.hit_count.unconditional_size_of(ops) +
+            self.last_access.unconditional_size_of(ops) +
             self.metadata.size_of(ops) +
             self.location_url.size_of(ops) +
             self.https_state.size_of(ops) +
@@ -91,6 +341,9 @@ struct CachedMetadata {
     /// Headers
     #[ignore_malloc_size_of = "Defined in `http` and has private members"]
     pub headers: Arc<Mutex<HeaderMap>>,
+    /// Trailers, received after the body.
+    #[ignore_malloc_size_of = "Defined in `http` and has private members"]
+    pub trailers: Arc<Mutex<HeaderMap>>,
     /// Final URL after redirects.
     pub final_url: ServoUrl,
     /// MIME type / subtype.
@@ -100,37 +353,333 @@ struct CachedMetadata {
     /// HTTP Status
     pub status: HttpStatus,
 }
+
+/// A read-only view of the metadata of a cached resource.
+pub struct CachedMetadataView<'a> {
+    /// Final URL after redirects.
+    pub final_url: &'a ServoUrl,
+    /// MIME type / subtype.
+    pub content_type: Option<&'a str>,
+    /// Character set.
+    pub charset: Option<&'a str>,
+    /// HTTP Status
+    pub status: &'a HttpStatus,
+    /// When the resource was stored, or last validated.
+    pub last_validated: Instant,
+}
+
+/// Information about a cached resource, for inspection purposes.
+#[derive(Clone, Debug)]
+pub struct CacheEntryInfo {
+    /// The URL used as the cache key.
+    pub url: ServoUrl,
+    /// HTTP Status
+    pub status: HttpStatus,
+    /// MIME type / subtype.
+    pub content_type: Option<String>,
+    /// Character set.
+    pub charset: Option<String>,
+    /// The freshness lifetime of the resource.
+    pub expires: Duration,
+    /// When the resource was stored, or last validated.
+    pub last_validated: Instant,
+    /// The size of the body as stored, compressed or not, if it has been fully received.
+    pub body_size: usize,
+    /// Whether the body is still being received.
+    pub is_receiving: bool,
+    /// How many times the resource has been served from the cache.
+    pub hit_count: u64,
+}
+
+impl CacheEntryInfo {
+    fn new(entry_key: &CacheKey, cached_resource: &CachedResource) -> CacheEntryInfo {
+        let (body_size, is_receiving) = match *cached_resource.lock_body() {
+            ResponseBody::Done(ref body) => (body.len(), false),
+            ResponseBody::Receiving(_) => (0, true),
+            ResponseBody::Empty => (0, false),
+        };
+        CacheEntryInfo {
+            url: entry_key.url.clone(),
+            status: cached_resource.metadata.status.clone(),
+            content_type: cached_resource.metadata.content_type.clone(),
+            charset: cached_resource.metadata.charset.clone(),
+            expires: cached_resource.expires,
+            last_validated: cached_resource.last_validated,
+            body_size,
+            is_receiving,
+            hit_count: cached_resource.hit_count.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A simplified HAR entry describing a cached resource, for network inspectors.
+/// <http://www.softwareishard.com/blog/har-12-spec/#entries>
+#[derive(Clone, Debug)]
+pub struct HarEntry {
+    /// The URL used as the cache key.
+    pub url: ServoUrl,
+    /// HTTP Status
+    pub status: HttpStatus,
+    /// The stored response headers, as name and value pairs.
+    pub headers: Vec<(String, String)>,
+    /// MIME type / subtype.
+    pub content_type: Option<String>,
+    /// The size of the body as stored, compressed or not, if it has been fully received.
+    pub body_size: usize,
+    /// The body, as it would be served, if it was requested and has been fully received.
+    pub body: Option<Vec<u8>>,
+    /// When the resource was stored, or last validated.
+    pub last_validated: Instant,
+}
+
+impl HarEntry {
+    fn new(entry_key: &CacheKey, cached_resource: &CachedResource, include_body: bool) -> HarEntry {
+        let headers = cached_resource
+            .lock_headers()
+            .iter()
+            .map(|(name, value)| {
+                (
+                    name.as_str().to_owned(),
+                    String::from_utf8_lossy(value.as_bytes()).into_owned(),
+                )
+            })
+            .collect();
+        let body_size = match *cached_resource.lock_body() {
+            ResponseBody::Done(ref body) => body.len(),
+            ResponseBody::Receiving(_) | ResponseBody::Empty => 0,
+        };
+        let body = include_body
+            .then(|| match *lock_unpoisoned(&served_body(cached_resource)) {
+                ResponseBody::Done(ref body) => Some(body.clone()),
+                ResponseBody::Receiving(_) | ResponseBody::Empty => None,
+            })
+            .flatten();
+        HarEntry {
+            url: entry_key.url.clone(),
+            status: cached_resource.metadata.status.clone(),
+            headers,
+            content_type: cached_resource.metadata.content_type.clone(),
+            body_size,
+            body,
+            last_validated: cached_resource.last_validated,
+        }
+    }
+}
+
+/// A resource stored under a url, along with what selects it among the other variants,
+/// to tell why a request did or didn't match it.
+/// <https://tools.ietf.org/html/rfc7234#section-4.1>
+#[derive(Clone, Debug)]
+pub struct VariantInfo {
+    /// HTTP Status
+    pub status: HttpStatus,
+    /// The stored `Vary` header value, if any.
+    pub vary: Option<String>,
+    /// The value of each request header field nominated by the `Vary` header,
+    /// by lowercased name, as sent by the request which stored the resource,
+    /// or `None` if it was absent.
+    pub selected_request_headers: Vec<(String, Option<String>)>,
+}
+
+impl VariantInfo {
+    fn new(cached_resource: &CachedResource) -> VariantInfo {
+        let vary = cached_resource
+            .lock_headers()
+            .get_all(header::VARY)
+            .iter()
+            .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned())
+            .reduce(|vary, value| format!("{vary}, {value}"));
+        let selected_request_headers = match cached_resource.secondary_key {
+            SecondaryKey::Any => vec![],
+            SecondaryKey::Fields(ref fields) => {
+                let request_headers = cached_resource.lock_request_headers();
+                fields
+                    .keys()
+                    .map(|name| {
+                        let value = request_headers
+                            .get(name.as_str())
+                            .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned());
+                        (name.clone(), value)
+                    })
+                    .collect()
+            },
+        };
+        VariantInfo {
+            status: cached_resource.metadata.status.clone(),
+            vary,
+            selected_request_headers,
+        }
+    }
+}
+
 /// Wrapper around a cached response, including information on re-validation needs
 pub struct CachedResponse {
     /// The response constructed from the cached resource
     pub response: Response,
     /// The revalidation flag for the stored response
     pub needs_validation: bool,
+    /// Whether the response is a range stitched together from several stored partial
+    /// responses, rather than sliced from a single resource.
+    /// Partial responses aren't combined yet, so this is always false.
+    pub from_partial_merge: bool,
+    /// The content type of the stored response, as parsed when it was stored.
+    pub content_type: Option<Mime>,
+    /// The character set of the stored response.
+    pub charset: Option<String>,
+}
+
+/// Why a request would, or wouldn't, be answered from the cache, see `HttpCache::explain`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CacheLookupExplanation {
+    /// Only GET requests are answered from the cache.
+    NotGet,
+    /// The request carries a no-store directive.
+    NoStoreRequest,
+    /// The cache mode of the request, "no-store" or "reload", bypasses the cache.
+    BypassedByCacheMode,
+    /// No resource is stored for the url, or only stubs whose body was dropped.
+    NoEntry,
+    /// The stored resources vary on a header, named here, whose value differs in the request.
+    VaryMismatch(String),
+    /// The stored resources were all aborted, or their fetch failed.
+    Aborted,
+    /// Only partial content is stored, which can't answer a request which isn't a range request.
+    OnlyPartialContent,
+    /// The stored resources are encoded with a coding the request doesn't accept,
+    /// and carry a no-transform directive forbidding the cache to decode them.
+    NoTransform,
+    /// A stored resource would be used, once validated.
+    NeedsValidation,
+    /// A stored resource would be used without validation.
+    Hit,
 }
 
 /// A memory cache.
-#[derive(Default, MallocSizeOf)]
+#[derive(MallocSizeOf)]
 pub struct HttpCache {
     /// cached responses.
     entries: HashMap<CacheKey, Vec<CachedResource>>,
+    /// The maximum total size of the stored bodies, if any.
+    memory_budget: Option<usize>,
+    /// The policy used to pick resources to evict when over the memory budget.
+    eviction_policy: EvictionPolicy,
+    /// Whether the cache is private or shared.
+    mode: HttpCacheMode,
+    /// The maximum size of a body to store, if any.
+    max_body_bytes: Option<usize>,
+    /// The maximum number of variants, selected by `Vary`, stored under a single key.
+    max_variants_per_key: usize,
+    /// The maximum number of distinct keys stored, if any.
+    max_keys: Option<usize>,
+    /// The pseudonym identifying the cache in the `Via` header of the responses it serves.
+    via_pseudonym: String,
+    /// The source of the current time.
+    #[ignore_malloc_size_of = "Trait object"]
+    clock: Box<dyn Clock>,
+    /// The freshness lifetime of responses lacking any freshness information, if any.
+    default_ttl: Option<Duration>,
+    /// The age past which a resource is never used, regardless of its freshness, if any.
+    max_entry_age: Option<Duration>,
+    /// Whether to store large bodies compressed, trading CPU for memory.
+    compress_bodies: bool,
+    /// Whether to hash complete bodies when storing them, to verify their integrity later.
+    hash_bodies: bool,
+    /// Whether to store responses to requests with a `Cookie` header,
+    /// or with a `Set-Cookie` header.
+    cache_with_cookies: bool,
+    /// The status codes to treat as cacheable by default, on top of those defined as such.
+    extra_cacheable_status: HashSet<StatusCode>,
+    /// The maximum heuristic freshness lifetime of responses with a given status code,
+    /// overriding the default of 24 hours.
+    heuristic_caps: HashMap<StatusCode, Duration>,
+    /// The bounds forced on the freshness lifetime of stored responses, if any.
+    cache_control_override: Option<CacheControlOverride>,
+    /// The channel on which to request the background revalidation of a url,
+    /// when a stale response is served under stale-while-revalidate.
+    #[ignore_malloc_size_of = "channels are hard"]
+    revalidation_sender: Option<TokioSender<ServoUrl>>,
+    /// The callback notified of evicted resources, if any.
+    #[ignore_malloc_size_of = "Trait object"]
+    on_evict: Option<EvictionCallback>,
+    /// The predicate which can veto the storage of responses, if any.
+    #[ignore_malloc_size_of = "Trait object"]
+    store_filter: Option<StoreFilter>,
+    /// The ranges of complete resources recently served, to serve repeated requests
+    /// for the same range without slicing their body again.
+    range_index: Mutex<RangeIndex>,
+}
+
+/// The default maximum heuristic freshness lifetime of a response.
+const DEFAULT_MAX_HEURISTIC: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The default pseudonym identifying the cache in the `Via` header of served responses.
+const DEFAULT_VIA_PSEUDONYM: &str = "servo-cache";
+
+/// The default maximum number of variants stored under a single key.
+const DEFAULT_MAX_VARIANTS_PER_KEY: usize = 10;
+
+impl Default for HttpCache {
+    fn default() -> HttpCache {
+        HttpCache {
+            entries: HashMap::new(),
+            memory_budget: None,
+            eviction_policy: EvictionPolicy::default(),
+            mode: HttpCacheMode::default(),
+            max_body_bytes: None,
+            max_variants_per_key: DEFAULT_MAX_VARIANTS_PER_KEY,
+            max_keys: None,
+            via_pseudonym: DEFAULT_VIA_PSEUDONYM.to_owned(),
+            clock: Box::new(SystemClock),
+            default_ttl: None,
+            max_entry_age: None,
+            compress_bodies: false,
+            hash_bodies: false,
+            cache_with_cookies: true,
+            extra_cacheable_status: HashSet::new(),
+            heuristic_caps: HashMap::new(),
+            cache_control_override: None,
+            revalidation_sender: None,
+            on_evict: None,
+            store_filter: None,
+            range_index: Mutex::new(RangeIndex::default()),
+        }
+    }
 }
 
-/// Determine if a response is cacheable by default <https://tools.ietf.org/html/rfc7231#section-6.1>
-fn is_cacheable_by_default(status_code: StatusCode) -> bool {
+/// Determine if a response is cacheable by default <https://tools.ietf.org/html/rfc7231#section-6.1>,
+/// or has been configured to be.
+fn is_cacheable_by_default(
+    status_code: StatusCode,
+    extra_cacheable_status: &HashSet<StatusCode>,
+) -> bool {
     matches!(
         status_code.as_u16(),
         200 | 203 | 204 | 206 | 300 | 301 | 404 | 405 | 410 | 414 | 501
-    )
+    ) || extra_cacheable_status.contains(&status_code)
 }
 
 /// Determine if a given response is cacheable.
 /// Based on <https://tools.ietf.org/html/rfc7234#section-3>
-fn response_is_cacheable(metadata: &Metadata) -> bool {
-    // TODO: if we determine that this cache should be considered shared:
-    // 1. check for absence of private response directive <https://tools.ietf.org/html/rfc7234#section-5.2.2.6>
-    // 2. check for absence of the Authorization header field.
+fn response_is_cacheable(
+    mode: HttpCacheMode,
+    metadata: &Metadata,
+    extra_cacheable_status: &HashSet<StatusCode>,
+) -> bool {
     let mut is_cacheable = false;
     let headers = metadata.headers.as_ref().unwrap();
+    if mode == HttpCacheMode::Shared && has_cache_control_directive(headers, "private") {
+        // A shared cache must not store a response intended for a single user,
+        // including one qualified with the field names which are private.
+        // <https://tools.ietf.org/html/rfc7234#section-5.2.2.6>
+        return false;
+    }
+    if metadata.status == StatusCode::PARTIAL_CONTENT &&
+        headers.typed_get::<ContentRange>().is_none()
+    {
+        // Range requests can't be answered from a partial response
+        // when the range it contains is unknown.
+        return false;
+    }
     if headers.contains_key(header::EXPIRES) ||
         headers.contains_key(header::LAST_MODIFIED) ||
         headers.contains_key(header::ETAG)
@@ -138,71 +687,242 @@ fn response_is_cacheable(metadata: &Metadata) -> bool {
         is_cacheable = true;
     }
     if let Some(ref directive) = headers.typed_get::<CacheControl>() {
-        if directive.no_store() {
+        // The typed header doesn't model must-understand.
+        if has_cache_control_directive(headers, "must-understand") {
+            // <https://www.rfc-editor.org/rfc/rfc9111#section-5.2.2.3>
+            // The caching requirements of the status codes this cache understands
+            // take precedence over no-store, other status codes are treated as if
+            // no-store was present.
+            match metadata.status.try_code() {
+                Some(code) if is_cacheable_by_default(code, extra_cacheable_status) => {
+                    is_cacheable = true
+                },
+                _ => return false,
+            }
+        } else if directive.no_store() {
             return false;
         }
-        if directive.public() ||
-            directive.s_max_age().is_some() ||
-            directive.max_age().is_some() ||
-            directive.no_cache()
-        {
+        if directive.public() || directive.s_max_age().is_some() || directive.max_age().is_some() {
             is_cacheable = true;
         }
     }
-    if let Some(pragma) = headers.typed_get::<Pragma>() {
-        if pragma.is_no_cache() {
-            return false;
-        }
+    // Both the unqualified no-cache directive, and the one qualified with field names,
+    // which the typed header doesn't model, allow storing the response.
+    // <https://tools.ietf.org/html/rfc7234#section-5.2.2.2>
+    if has_cache_control_directive(headers, "no-cache") {
+        is_cacheable = true;
+    }
+    // Pragma is only a fallback for HTTP/1.0 caches, ignored when Cache-Control is present.
+    // <https://tools.ietf.org/html/rfc7234#section-5.4>
+    if !headers.contains_key(header::CACHE_CONTROL) &&
+        headers
+            .typed_get::<Pragma>()
+            .is_some_and(|pragma| pragma.is_no_cache())
+    {
+        return false;
     }
     is_cacheable
 }
 
+/// Parse the raw Cache-Control directives as `(name, argument)` pairs,
+/// for the directives, or forms of directives, not modeled by the typed header.
+/// <https://tools.ietf.org/html/rfc7234#section-5.2>
+fn cache_control_directives(headers: &HeaderMap) -> Vec<(String, Option<String>)> {
+    let mut directives = vec![];
+    for value in headers.get_all(header::CACHE_CONTROL) {
+        let Ok(value) = value.to_str() else {
+            continue;
+        };
+        // Split on commas, except those inside a quoted-string argument.
+        let mut in_quotes = false;
+        let raw_directives = value.split(|c| {
+            if c == '"' {
+                in_quotes = !in_quotes;
+            }
+            c == ',' && !in_quotes
+        });
+        for raw_directive in raw_directives {
+            let (name, argument) = match raw_directive.split_once('=') {
+                Some((name, argument)) => (name, Some(argument.trim().trim_matches('"'))),
+                None => (raw_directive, None),
+            };
+            let name = name.trim().to_ascii_lowercase();
+            if name.is_empty() {
+                continue;
+            }
+            directives.push((name, argument.map(str::to_owned)));
+        }
+    }
+    directives
+}
+
+/// Whether the raw Cache-Control directives include one with the given name.
+fn has_cache_control_directive(headers: &HeaderMap, directive_name: &str) -> bool {
+    cache_control_directives(headers)
+        .iter()
+        .any(|(name, _)| name == directive_name)
+}
+
+/// The window during which a stale response can be served while it is revalidated
+/// in the background, from the `stale-while-revalidate` response directive.
+/// <https://tools.ietf.org/html/rfc5861#section-3>
+fn stale_while_revalidate(headers: &HeaderMap) -> Option<Duration> {
+    cache_control_directives(headers)
+        .into_iter()
+        .filter(|(name, _)| name == "stale-while-revalidate")
+        .find_map(|(_, argument)| argument?.parse().ok())
+        .map(Duration::from_secs)
+}
+
+/// The header field names listed by a qualified `no-cache="..."` response directive,
+/// which can't be sent in a response without successful revalidation.
+/// <https://tools.ietf.org/html/rfc7234#section-5.2.2.2>
+fn qualified_no_cache_fields(headers: &HeaderMap) -> Vec<String> {
+    cache_control_directives(headers)
+        .into_iter()
+        .filter(|(name, _)| name == "no-cache")
+        .filter_map(|(_, argument)| argument)
+        .flat_map(|fields| {
+            fields
+                .split(',')
+                .map(|field| field.trim().to_ascii_lowercase())
+                .filter(|field| !field.is_empty())
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+/// The largest delta-seconds value to use, larger values being clamped to it.
+/// <https://tools.ietf.org/html/rfc7234#section-1.2.1>
+const MAX_DELTA_SECONDS: u64 = 2147483648;
+
+/// The value of the first valid delta-seconds directive with the given name,
+/// ignoring those with a malformed argument.
+/// <https://tools.ietf.org/html/rfc7234#section-1.2.1>
+fn delta_seconds_directive(
+    directives: &[(String, Option<String>)],
+    directive_name: &str,
+) -> Option<Duration> {
+    directives
+        .iter()
+        .filter(|(name, _)| name == directive_name)
+        .filter_map(|(_, argument)| argument.as_deref())
+        .find(|argument| !argument.is_empty() && argument.bytes().all(|byte| byte.is_ascii_digit()))
+        .map(|argument| {
+            // The digits may not fit in a u64, in which case they are clamped too.
+            let seconds = argument.parse::<u64>().unwrap_or(u64::MAX);
+            Duration::from_secs(seconds.min(MAX_DELTA_SECONDS))
+        })
+}
+
 /// Calculating Age
 /// <https://tools.ietf.org/html/rfc7234#section-4.2.3>
 fn calculate_response_age(response: &Response) -> Duration {
     // TODO: follow the spec more closely (Date headers, request/response lag, ...)
-    response
-        .headers
-        .get(header::AGE)
-        .and_then(|age_header| age_header.to_str().ok())
-        .and_then(|age_string| age_string.parse::<u64>().ok())
-        .map(Duration::from_secs)
-        .unwrap_or_default()
+    let Some(age_header) = response.headers.get(header::AGE) else {
+        return Duration::ZERO;
+    };
+    let age_string = age_header.to_str().unwrap_or_default().trim();
+    // Be lenient with values combined by an intermediary, or with a fractional part,
+    // by only using the whole seconds of the first one.
+    let first_value = age_string.split(',').next().unwrap_or_default().trim();
+    let whole_seconds = first_value.split('.').next().unwrap_or_default();
+    if whole_seconds.is_empty() || !whole_seconds.bytes().all(|byte| byte.is_ascii_digit()) {
+        debug!("ignoring malformed Age header {:?}", age_header);
+        return Duration::ZERO;
+    }
+    if whole_seconds != age_string {
+        debug!("leniently parsing malformed Age header {:?}", age_header);
+    }
+    // The digits may not fit in a u64, in which case they are clamped too.
+    let age = whole_seconds
+        .parse::<u64>()
+        .unwrap_or(u64::MAX)
+        .min(MAX_DELTA_SECONDS);
+    Duration::from_secs(age)
 }
 
 /// Determine the expiry date from relevant headers,
-/// or uses a heuristic if none are present.
-fn get_response_expiry(response: &Response) -> Duration {
+/// or uses a heuristic if none are present, the default time-to-live of the cache,
+/// if any, replacing the heuristic of responses without a Last-Modified header.
+fn get_response_expiry(
+    response: &Response,
+    now: SystemTime,
+    default_ttl: Option<Duration>,
+    extra_cacheable_status: &HashSet<StatusCode>,
+    heuristic_caps: &HashMap<StatusCode, Duration>,
+    mode: HttpCacheMode,
+) -> Duration {
     // Calculating Freshness Lifetime <https://tools.ietf.org/html/rfc7234#section-4.2.1>
     let age = calculate_response_age(response);
-    let now = SystemTime::now();
-    if let Some(directives) = response.headers.typed_get::<CacheControl>() {
-        if directives.no_cache() {
-            // Requires validation on first use.
-            return Duration::ZERO;
-        }
-        if let Some(max_age) = directives.max_age().or(directives.s_max_age()) {
-            return max_age.saturating_sub(age);
-        }
+    // The raw directives are used, so that a malformed directive doesn't hide valid ones.
+    let directives = cache_control_directives(&response.headers);
+    if directives
+        .iter()
+        .any(|(name, argument)| name == "no-cache" && argument.is_none())
+    {
+        // Requires validation on first use.
+        return Duration::ZERO;
+    }
+    // s-maxage only applies to shared caches, in which it overrides max-age.
+    // <https://tools.ietf.org/html/rfc7234#section-5.2.2.9>
+    // Either of them takes precedence over Expires, which is only used without them.
+    let max_age = match mode {
+        HttpCacheMode::Shared => delta_seconds_directive(&directives, "s-maxage")
+            .or_else(|| delta_seconds_directive(&directives, "max-age")),
+        HttpCacheMode::Private => delta_seconds_directive(&directives, "max-age"),
+    };
+    if let Some(max_age) = max_age {
+        return max_age.saturating_sub(age);
     }
     match response.headers.typed_get::<Expires>() {
         Some(expiry) => {
-            // `duration_since` fails if `now` is later than `expiry_time` in which case,
-            // this whole thing return `Duration::ZERO`.
+            // The freshness lifetime is the time between the Expires and Date headers,
+            // both set by the origin server, so that it isn't affected by clock skew.
+            // Without a valid Date header, the current time is used instead.
+            // <https://tools.ietf.org/html/rfc7234#section-4.2.1>
+            let date_time: SystemTime = response
+                .headers
+                .typed_get::<Date>()
+                .map(SystemTime::from)
+                .unwrap_or(now);
+            // An Expires date at or before the Date header, such as one in 1970, is the
+            // intentional way to mark the response as already stale, in which case
+            // `duration_since` either fails or returns `Duration::ZERO`.
             let expiry_time: SystemTime = expiry.into();
-            return expiry_time.duration_since(now).unwrap_or(Duration::ZERO);
+            let freshness_lifetime = expiry_time
+                .duration_since(date_time)
+                .unwrap_or(Duration::ZERO);
+            // The current age is the larger of the Age header, and of the apparent age,
+            // the time elapsed since the Date header.
+            // <https://tools.ietf.org/html/rfc7234#section-4.2.3>
+            let apparent_age = now.duration_since(date_time).unwrap_or_default();
+            return freshness_lifetime.saturating_sub(age.max(apparent_age));
+        },
+        None => {
+            if let Some(expires) = response.headers.get(header::EXPIRES) {
+                // Invalid dates, especially the common "0", represent a time in the past,
+                // the response is already stale.
+                // <https://tools.ietf.org/html/rfc7234#section-5.3>
+                if expires.as_bytes().trim_ascii() != b"0" {
+                    debug!("treating malformed Expires header {:?} as expired", expires);
+                }
+                return Duration::ZERO;
+            }
         },
-        // Malformed Expires header, shouldn't be used to construct a valid response.
-        None if response.headers.contains_key(header::EXPIRES) => return Duration::ZERO,
-        _ => {},
     }
     // Calculating Heuristic Freshness
     // <https://tools.ietf.org/html/rfc7234#section-4.2.2>
     if let Some(ref code) = response.status.try_code() {
         // <https://tools.ietf.org/html/rfc7234#section-5.5.4>
         // Since presently we do not generate a Warning header field with a 113 warn-code,
-        // 24 hours minus response age is the max for heuristic calculation.
-        let max_heuristic = Duration::from_secs(24 * 60 * 60).saturating_sub(age);
+        // 24 hours minus response age is the max for heuristic calculation,
+        // unless a different cap was configured for the status code.
+        let max_heuristic = heuristic_caps
+            .get(code)
+            .copied()
+            .unwrap_or(DEFAULT_MAX_HEURISTIC)
+            .saturating_sub(age);
         let heuristic_freshness = if let Some(last_modified) =
             // If the response has a Last-Modified header field,
             // caches are encouraged to use a heuristic expiration value
@@ -222,9 +942,11 @@ fn get_response_expiry(response: &Response) -> Duration {
                 max_heuristic
             }
         } else {
-            max_heuristic
+            // Without a Last-Modified header, there is nothing to base a heuristic on,
+            // the default time-to-live of the cache is used if configured.
+            default_ttl.unwrap_or(max_heuristic)
         };
-        if is_cacheable_by_default(*code) {
+        if is_cacheable_by_default(*code, extra_cacheable_status) {
             // Status codes that are cacheable by default can use heuristics to determine freshness.
             return heuristic_freshness;
         }
@@ -236,278 +958,1361 @@ fn get_response_expiry(response: &Response) -> Duration {
         }
     }
     // Requires validation upon first use as default.
-    Duration::ZERO
+    default_ttl.unwrap_or(Duration::ZERO)
 }
 
 /// Request Cache-Control Directives
 /// <https://tools.ietf.org/html/rfc7234#section-5.2.1>
+///
+/// Every directive present constrains the result, so the most restrictive one wins.
 fn get_expiry_adjustment_from_request_headers(request: &Request, expires: Duration) -> Duration {
     let Some(directive) = request.headers.typed_get::<CacheControl>() else {
         return expires;
     };
 
-    if let Some(max_age) = directive.max_stale() {
-        return expires + max_age;
-    }
-
-    match directive.max_age() {
-        Some(max_age) if expires > max_age => return Duration::ZERO,
-        Some(max_age) => return expires - max_age,
-        None => {},
+    // The client is willing to accept a response that has exceeded its freshness lifetime.
+    // Without an argument, which the typed header ignores, max-stale accepts a response
+    // of any staleness.
+    let mut adjusted_expires = match directive.max_stale() {
+        Some(max_stale) => expires.saturating_add(max_stale),
+        None if cache_control_directives(&request.headers)
+            .iter()
+            .any(|(name, argument)| name == "max-stale" && argument.is_none()) =>
+        {
+            Duration::MAX
+        },
+        None => expires,
     };
 
+    // The client won't accept a response older than max-age,
+    // with max-age=0 forcing the stored one to be revalidated.
+    if let Some(max_age) = directive.max_age() {
+        adjusted_expires = adjusted_expires.min(max_age);
+    }
+
+    // The client wants a response that will still be fresh for at least min-fresh.
     if let Some(min_fresh) = directive.min_fresh() {
-        if expires < min_fresh {
-            return Duration::ZERO;
-        }
-        return expires - min_fresh;
+        adjusted_expires = adjusted_expires.min(expires.saturating_sub(min_fresh));
     }
 
-    if directive.no_cache() || directive.no_store() {
-        return Duration::ZERO;
+    adjusted_expires
+}
+
+/// Whether the request asks for an end-to-end revalidation, with a `no-cache`
+/// Cache-Control directive, or a `Pragma: no-cache` in the absence of Cache-Control,
+/// or with the "no-cache" cache mode.
+/// The stored resource can still be used for the validators of a conditional request.
+/// <https://tools.ietf.org/html/rfc7234#section-5.2.1.4>
+/// <https://tools.ietf.org/html/rfc7234#section-5.4>
+/// <https://fetch.spec.whatwg.org/#concept-request-cache-mode>
+fn request_forces_revalidation(request: &Request) -> bool {
+    if request.cache_mode == CacheMode::NoCache {
+        return true;
+    }
+    match request.headers.typed_get::<CacheControl>() {
+        Some(directive) => directive.no_cache(),
+        None => request
+            .headers
+            .typed_get::<Pragma>()
+            .is_some_and(|pragma| pragma.is_no_cache()),
+    }
+}
+
+/// Whether a response to a request with an Authorization header field can be stored.
+/// A private cache can store it, but a shared cache only when the response explicitly
+/// allows it with the must-revalidate, public, or s-maxage directives.
+/// <https://tools.ietf.org/html/rfc7234#section-3.2>
+fn authorized_response_is_storable(mode: HttpCacheMode, headers: &HeaderMap) -> bool {
+    match mode {
+        HttpCacheMode::Private => true,
+        HttpCacheMode::Shared => ["must-revalidate", "public", "s-maxage"]
+            .iter()
+            .any(|directive| has_cache_control_directive(headers, directive)),
     }
+}
 
-    expires
+/// Whether the cache mode of the request, "no-store" or "reload",
+/// asks for the cache not to be consulted.
+/// <https://fetch.spec.whatwg.org/#concept-request-cache-mode>
+fn cache_mode_bypasses_cache(request: &Request) -> bool {
+    matches!(request.cache_mode, CacheMode::NoStore | CacheMode::Reload)
 }
 
-/// Create a CachedResponse from a request and a CachedResource.
-fn create_cached_response(
-    request: &Request,
-    cached_resource: &CachedResource,
-    cached_headers: &HeaderMap,
-    done_chan: &mut DoneChannel,
-) -> Option<CachedResponse> {
-    debug!("creating a cached response for {:?}", request.url());
-    if cached_resource.aborted.load(Ordering::Acquire) {
-        return None;
+/// Remove the hop-by-hop headers, including those nominated by the `Connection` header,
+/// so that only the end-to-end headers of a response are stored and served.
+/// <https://tools.ietf.org/html/rfc7230#section-6.1>
+fn strip_hop_by_hop_headers(headers: &mut HeaderMap) {
+    let nominated: Vec<String> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|field| field.trim().to_ascii_lowercase())
+        .filter(|field| !field.is_empty())
+        .collect();
+    for field in nominated {
+        headers.remove(field.as_str());
     }
-    let resource_timing = ResourceFetchTiming::new(request.timing_type());
-    let mut response = Response::new(cached_resource.metadata.final_url.clone(), resource_timing);
-    response.headers = cached_headers.clone();
-    response.body = cached_resource.body.clone();
-    if let ResponseBody::Receiving(_) = *cached_resource.body.lock().unwrap() {
-        debug!("existing body is in progress");
-        let (done_sender, done_receiver) = unbounded();
-        *done_chan = Some((done_sender.clone(), done_receiver));
-        cached_resource
-            .awaiting_body
-            .lock()
-            .unwrap()
-            .push(done_sender);
-    }
-    response
-        .location_url
-        .clone_from(&cached_resource.location_url);
-    response.status.clone_from(&cached_resource.status);
-    response.url_list.clone_from(&cached_resource.url_list);
-    response.https_state = cached_resource.https_state;
-    response.referrer = request.referrer.to_url().cloned();
-    response.referrer_policy = request.referrer_policy;
-    response.aborted = cached_resource.aborted.clone();
-
-    let expires = cached_resource.expires;
-    let adjusted_expires = get_expiry_adjustment_from_request_headers(request, expires);
-    let time_since_validated = Instant::now() - cached_resource.last_validated;
-
-    // TODO: take must-revalidate into account <https://tools.ietf.org/html/rfc7234#section-5.2.2.1>
-    // TODO: if this cache is to be considered shared, take proxy-revalidate into account
-    // <https://tools.ietf.org/html/rfc7234#section-5.2.2.7>
-    let has_expired = adjusted_expires <= time_since_validated;
-    let cached_response = CachedResponse {
-        response,
-        needs_validation: has_expired,
+    for field in [
+        header::CONNECTION,
+        HeaderName::from_static("keep-alive"),
+        header::PROXY_AUTHENTICATE,
+        header::PROXY_AUTHORIZATION,
+        header::TE,
+        header::TRANSFER_ENCODING,
+        header::UPGRADE,
+    ] {
+        headers.remove(field);
+    }
+}
+
+/// Remove the fields of stored headers which mustn't be served to other users of the cache,
+/// a shared cache never stores the cookies set for one of them.
+/// <https://tools.ietf.org/html/rfc7234#section-8>
+fn strip_user_specific_headers(mode: HttpCacheMode, headers: &mut HeaderMap) {
+    if mode == HttpCacheMode::Shared {
+        headers.remove(header::SET_COOKIE);
+    }
+}
+
+/// Whether the validators sent by a conditional request still match those of a response,
+/// in which case the client can be sent a 304 instead of the full response.
+/// <https://tools.ietf.org/html/rfc7232#section-6>
+fn request_validators_match(request: &Request, headers: &HeaderMap) -> bool {
+    if let Some(if_none_match) = request.headers.typed_get::<IfNoneMatch>() {
+        return headers
+            .typed_get::<ETag>()
+            .is_some_and(|etag| !if_none_match.precondition_passes(&etag));
+    }
+    match (
+        request.headers.typed_get::<IfModifiedSince>(),
+        headers.typed_get::<LastModified>(),
+    ) {
+        (Some(if_modified_since), Some(last_modified)) => {
+            !if_modified_since.is_modified(last_modified.into())
+        },
+        _ => false,
+    }
+}
+
+/// The opaque part of an entity-tag, used for the weak comparison of entity-tags,
+/// where two tags match whether either of them is weak.
+/// <https://tools.ietf.org/html/rfc7232#section-2.3.2>
+fn opaque_tag(entity_tag: &HeaderValue) -> &[u8] {
+    let entity_tag = entity_tag.as_bytes();
+    entity_tag.strip_prefix(b"W/").unwrap_or(entity_tag)
+}
+
+/// Whether the validators of a 304 response select a stored resource: its entity-tag
+/// if it has one, otherwise its last modification date, if it has one.
+/// <https://tools.ietf.org/html/rfc7234#section-4.3.4>
+fn validators_match(cached_resource: &CachedResource, not_modified_headers: &HeaderMap) -> bool {
+    let cached_headers = cached_resource.lock_headers();
+    if let Some(etag) = not_modified_headers.get(header::ETAG) {
+        return cached_headers
+            .get(header::ETAG)
+            .is_some_and(|cached_etag| opaque_tag(cached_etag) == opaque_tag(etag));
+    }
+    if let Some(last_modified) = not_modified_headers.typed_get::<LastModified>() {
+        return cached_headers.typed_get::<LastModified>() == Some(last_modified);
+    }
+    true
+}
+
+/// Whether the stored request headers selected by the `Vary` header of a cached resource
+/// match those of the current request.
+/// Calculating Secondary Keys with Vary <https://tools.ietf.org/html/rfc7234#section-4.1>
+fn vary_matches(request: &Request, cached_resource: &CachedResource) -> bool {
+    vary_mismatch(request, cached_resource).is_none()
+}
+
+/// The first header field named by the `Vary` header of a cached resource whose value
+/// differs between the original and the current request, or `*` for `Vary: *`.
+fn vary_mismatch(request: &Request, cached_resource: &CachedResource) -> Option<String> {
+    let fields = match cached_resource.secondary_key {
+        SecondaryKey::Any => {
+            debug!("vary value is any, not caching");
+            return Some("*".to_owned());
+        },
+        SecondaryKey::Fields(ref fields) => fields,
+    };
+    // If a header field is absent from a request, it can only match a stored response
+    // if it was also absent from the original request, and the other way around.
+    let (vary_val, _) = fields.iter().find(|(vary_val, original_value)| {
+        selected_field_value(vary_val, &request.headers) != **original_value
+    })?;
+    debug!("vary header {} doesn't match, not caching", vary_val);
+    Some(vary_val.clone())
+}
+
+/// The values of the request header fields nominated by the `Vary` header of a stored
+/// response, normalized to be compared with those of subsequent requests.
+/// Calculating Secondary Keys with Vary <https://tools.ietf.org/html/rfc7234#section-4.1>
+#[derive(Clone, Debug, MallocSizeOf, PartialEq)]
+enum SecondaryKey {
+    /// A `Vary: *` header, which never matches.
+    Any,
+    /// The value of each nominated field, by lowercased name, or `None` if it was absent.
+    Fields(BTreeMap<String, Option<Vec<u8>>>),
+}
+
+/// The secondary key of a response with the given headers, to the given request headers.
+/// Each field is listed once, as a server can repeat them or split them over several
+/// `Vary` headers.
+fn secondary_key(response_headers: &HeaderMap, request_headers: &HeaderMap) -> SecondaryKey {
+    let Some(vary) = response_headers.typed_get::<Vary>() else {
+        return SecondaryKey::Fields(BTreeMap::new());
     };
-    Some(cached_response)
+    if vary.is_any() {
+        return SecondaryKey::Any;
+    }
+    let fields = vary
+        .iter_strs()
+        .map(|vary_val| vary_val.trim().to_ascii_lowercase())
+        .map(|vary_val| {
+            let value = selected_field_value(&vary_val, request_headers);
+            (vary_val, value)
+        })
+        .collect();
+    SecondaryKey::Fields(fields)
+}
+
+/// The normalized value of a request header field nominated by a `Vary` header,
+/// or `None` if it is absent. Accept-Encoding values are normalized to the sets of
+/// codings they accept, regardless of their order and weights.
+fn selected_field_value(name: &str, request_headers: &HeaderMap) -> Option<Vec<u8>> {
+    let value = request_headers.get(name)?;
+    if name != "accept-encoding" {
+        return Some(value.as_bytes().to_vec());
+    }
+    let mut codings: Vec<String> = accepted_codings(request_headers).into_iter().collect();
+    codings.sort();
+    Some(codings.join(",").into_bytes())
 }
 
 /// Create a new resource, based on the bytes requested, and an existing resource,
 /// with a status-code of 206.
 fn create_resource_with_bytes_from_resource(
-    bytes: &[u8],
+    bytes: Vec<u8>,
     resource: &CachedResource,
 ) -> CachedResource {
     CachedResource {
-        request_headers: resource.request_headers.clone(),
-        body: Arc::new(Mutex::new(ResponseBody::Done(bytes.to_owned()))),
+        body: Arc::new(Mutex::new(ResponseBody::Done(bytes))),
+        body_compressed: false,
+        body_hash: None,
+        body_dropped: false,
         aborted: Arc::new(AtomicBool::new(false)),
         awaiting_body: Arc::new(Mutex::new(vec![])),
-        metadata: resource.metadata.clone(),
-        location_url: resource.location_url.clone(),
-        https_state: resource.https_state,
+        producer: Arc::new(Mutex::new(BodyProducer::Original)),
+        outcome: Arc::new(Mutex::new(FetchOutcome::Completed)),
         status: StatusCode::PARTIAL_CONTENT.into(),
-        url_list: resource.url_list.clone(),
-        expires: resource.expires,
-        last_validated: resource.last_validated,
+        ..resource.clone()
     }
 }
 
-/// Support for range requests <https://tools.ietf.org/html/rfc7233>.
-fn handle_range_request(
-    request: &Request,
-    candidates: &[&CachedResource],
-    range_spec: &Range,
-    done_chan: &mut DoneChannel,
-) -> Option<CachedResponse> {
-    let mut complete_cached_resources = candidates
-        .iter()
-        .filter(|resource| resource.status == StatusCode::OK);
-    let partial_cached_resources = candidates
-        .iter()
-        .filter(|resource| resource.status == StatusCode::PARTIAL_CONTENT);
-    if let Some(complete_resource) = complete_cached_resources.next() {
-        // TODO: take the full range spec into account.
-        // If we have a complete resource, take the request range from the body.
-        // When there isn't a complete resource available, we loop over cached partials,
-        // and see if any individual partial response can fulfill the current request for a bytes range.
-        // TODO: combine partials that in combination could satisfy the requested range?
-        // see <https://tools.ietf.org/html/rfc7233#section-4.3>.
-        // TODO: add support for complete and partial resources,
-        // whose body is in the ResponseBody::Receiving state.
-        let body_len = match *complete_resource.body.lock().unwrap() {
-            ResponseBody::Done(ref body) => body.len(),
-            _ => 0,
-        };
-        let bound = range_spec
-            .satisfiable_ranges(body_len.try_into().unwrap())
-            .next()
-            .unwrap();
-        match bound {
-            (Bound::Included(beginning), Bound::Included(end)) => {
-                if let ResponseBody::Done(ref body) = *complete_resource.body.lock().unwrap() {
-                    if end == u64::MAX {
-                        // Prevent overflow on the addition below.
-                        return None;
-                    }
-                    let b = beginning as usize;
-                    let e = end as usize + 1;
-                    let requested = body.get(b..e);
-                    if let Some(bytes) = requested {
-                        let new_resource =
-                            create_resource_with_bytes_from_resource(bytes, complete_resource);
-                        let cached_headers = new_resource.metadata.headers.lock().unwrap();
-                        let cached_response = create_cached_response(
-                            request,
-                            &new_resource,
-                            &cached_headers,
-                            done_chan,
-                        );
-                        if let Some(cached_response) = cached_response {
-                            return Some(cached_response);
-                        }
-                    }
-                }
-            },
-            (Bound::Included(beginning), Bound::Unbounded) => {
-                if let ResponseBody::Done(ref body) = *complete_resource.body.lock().unwrap() {
-                    let b = beginning as usize;
-                    let requested = body.get(b..);
-                    if let Some(bytes) = requested {
-                        let new_resource =
-                            create_resource_with_bytes_from_resource(bytes, complete_resource);
-                        let cached_headers = new_resource.metadata.headers.lock().unwrap();
-                        let cached_response = create_cached_response(
-                            request,
-                            &new_resource,
-                            &cached_headers,
-                            done_chan,
-                        );
-                        if let Some(cached_response) = cached_response {
-                            return Some(cached_response);
-                        }
-                    }
-                }
-            },
-            _ => return None,
-        }
-    } else {
-        for partial_resource in partial_cached_resources {
-            let headers = partial_resource.metadata.headers.lock().unwrap();
-            let content_range = headers.typed_get::<ContentRange>();
+/// A range of a resource recently served, kept ready to serve repeated requests for it.
+#[derive(MallocSizeOf)]
+struct IndexedRange {
+    entry_key: CacheKey,
+    /// The requested range, which resolves to the same bytes as long as the source
+    /// resource is the same, so that it can be looked up without decompressing its body.
+    #[ignore_malloc_size_of = "Defined in `headers`"]
+    range: Range,
+    /// The hit counter of the resource the range was sliced from, which identifies it,
+    /// as it is shared with the copies decompressing its body.
+    #[ignore_malloc_size_of = "Shared with the source resource"]
+    source: Arc<AtomicU64>,
+    resource: CachedResource,
+}
 
-            let Some(body_len) = content_range.as_ref().and_then(|range| range.bytes_len()) else {
-                continue;
-            };
-            match range_spec.satisfiable_ranges(body_len - 1).next().unwrap() {
-                (Bound::Included(beginning), Bound::Included(end)) => {
-                    let (res_beginning, res_end) = match content_range {
-                        Some(range) => {
-                            if let Some(bytes_range) = range.bytes_range() {
-                                bytes_range
-                            } else {
-                                continue;
-                            }
-                        },
-                        _ => continue,
-                    };
-                    if res_beginning <= beginning && res_end >= end {
-                        let resource_body = &*partial_resource.body.lock().unwrap();
-                        let requested = match resource_body {
-                            ResponseBody::Done(body) => {
-                                let b = beginning as usize - res_beginning as usize;
-                                let e = end as usize - res_beginning as usize + 1;
-                                body.get(b..e)
-                            },
-                            _ => continue,
-                        };
-                        if let Some(bytes) = requested {
-                            let new_resource =
-                                create_resource_with_bytes_from_resource(bytes, partial_resource);
-                            let cached_response =
-                                create_cached_response(request, &new_resource, &headers, done_chan);
-                            if let Some(cached_response) = cached_response {
-                                return Some(cached_response);
-                            }
-                        }
-                    }
-                },
+/// A small index of the ranges of complete resources recently served,
+/// evicting the least recently used range when over its capacity.
+#[derive(Default, MallocSizeOf)]
+struct RangeIndex {
+    capacity: usize,
+    ranges: VecDeque<IndexedRange>,
+    hits: u64,
+}
 
-                (Bound::Included(beginning), Bound::Unbounded) => {
-                    let (res_beginning, res_end, total) = if let Some(range) = content_range {
-                        match (range.bytes_range(), range.bytes_len()) {
-                            (Some(bytes_range), Some(total)) => {
-                                (bytes_range.0, bytes_range.1, total)
-                            },
-                            _ => continue,
-                        }
-                    } else {
-                        continue;
-                    };
-                    if total == 0 {
-                        // Prevent overflow in the below operations from occuring.
-                        continue;
-                    };
-                    if res_beginning <= beginning && res_end == total - 1 {
-                        let resource_body = &*partial_resource.body.lock().unwrap();
-                        let requested = match resource_body {
-                            ResponseBody::Done(body) => {
-                                let from_byte = beginning as usize - res_beginning as usize;
-                                body.get(from_byte..)
+impl RangeIndex {
+    fn get(
+        &mut self,
+        entry_key: &CacheKey,
+        range: &Range,
+        source: &CachedResource,
+    ) -> Option<CachedResource> {
+        let position = self.ranges.iter().position(|indexed| {
+            indexed.entry_key == *entry_key &&
+                indexed.range == *range &&
+                Arc::ptr_eq(&indexed.source, &source.hit_count)
+        })?;
+        let indexed = self.ranges.remove(position)?;
+        let mut resource = indexed.resource.clone();
+        self.ranges.push_back(indexed);
+        self.hits += 1;
+        // The source may have been refreshed, or invalidated, since the range was sliced.
+        resource.expires = source.expires;
+        resource.last_validated = source.last_validated;
+        Some(resource)
+    }
+
+    fn insert(
+        &mut self,
+        entry_key: &CacheKey,
+        range: &Range,
+        source: &CachedResource,
+        resource: &CachedResource,
+    ) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.ranges.len() >= self.capacity {
+            self.ranges.pop_front();
+        }
+        self.ranges.push_back(IndexedRange {
+            entry_key: entry_key.clone(),
+            range: range.clone(),
+            source: source.hit_count.clone(),
+            resource: resource.clone(),
+        });
+    }
+
+    fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.ranges.len() > capacity {
+            self.ranges.pop_front();
+        }
+    }
+
+    /// Forget the ranges sliced from a resource no longer stored.
+    fn remove_source(&mut self, source: &CachedResource) {
+        self.ranges
+            .retain(|indexed| !Arc::ptr_eq(&indexed.source, &source.hit_count));
+    }
+
+    /// Forget all ranges, keeping the capacity and the hit counter.
+    fn clear(&mut self) {
+        self.ranges.clear();
+    }
+
+    /// The total size of the bodies of the ranges.
+    fn body_size(&self) -> usize {
+        self.ranges
+            .iter()
+            .map(|indexed| resource_body_size(&indexed.resource))
+            .sum()
+    }
+}
+
+/// Notify the eviction callback, if any, that a resource was evicted,
+/// and the consumers still awaiting its body, which would otherwise wait forever.
+/// The ranges sliced from it are forgotten.
+fn notify_eviction(
+    on_evict: &Option<EvictionCallback>,
+    range_index: &Mutex<RangeIndex>,
+    entry_key: &CacheKey,
+    evicted: &CachedResource,
+) {
+    cancel_awaiting_consumers(evicted);
+    lock_unpoisoned(range_index).remove_source(evicted);
+    if let Some(on_evict) = on_evict {
+        on_evict(&entry_key.url, resource_body_size(evicted));
+    }
+}
+
+/// Whether consumers are awaiting the body of a resource, which then shouldn't be evicted.
+fn has_awaiting_consumers(resource: &CachedResource) -> bool {
+    !lock_unpoisoned(&resource.awaiting_body).is_empty()
+}
+
+/// Have the consumers awaiting the body of a resource removed from the cache
+/// start a new network request instead.
+fn cancel_awaiting_consumers(resource: &CachedResource) {
+    for done_sender in lock_unpoisoned(&resource.awaiting_body).drain(..) {
+        let _ = done_sender.send(Data::Cancelled);
+    }
+}
+
+/// Wake-up the first consumer in line, which will start a new network request and become
+/// the producer on which the others wait. Consumers which stopped waiting are skipped,
+/// as their fetch would never complete.
+fn promote_next_consumer(awaiting_consumers: &mut Vec<TokioSender<Data>>) {
+    while !awaiting_consumers.is_empty() {
+        let done_sender = awaiting_consumers.remove(0);
+        if done_sender.send(Data::Cancelled).is_ok() {
+            return;
+        }
+    }
+}
+
+/// Append the cache, identified by a pseudonym, to the `Via` header of a served response,
+/// unless it is already listed.
+/// <https://tools.ietf.org/html/rfc7230#section-5.7.1>
+fn append_via(headers: &mut HeaderMap, pseudonym: &str) {
+    let received_by = format!("1.1 {pseudonym}");
+    let already_listed = headers
+        .get_all(header::VIA)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|entry| entry.trim() == received_by);
+    if already_listed {
+        return;
+    }
+    if let Ok(value) = HeaderValue::from_str(&received_by) {
+        headers.append(header::VIA, value);
+    }
+}
+
+/// The headers of a 206 response synthesized from a stored resource,
+/// advertising that range requests are supported.
+/// <https://tools.ietf.org/html/rfc7233#section-2.3>
+fn range_response_headers(headers: &HeaderMap) -> HeaderMap {
+    let mut range_headers = headers.clone();
+    range_headers.typed_insert(AcceptRanges::bytes());
+    range_headers
+}
+
+/// Whether the `Accept-Encoding` header of a request allows a content-coding.
+/// <https://httpwg.org/specs/rfc9110.html#field.accept-encoding>
+fn request_accepts_coding(request: &Request, coding: &str) -> bool {
+    if !request.headers.contains_key(header::ACCEPT_ENCODING) {
+        // Without an Accept-Encoding header, any content-coding is acceptable.
+        return true;
+    }
+    accepted_codings(&request.headers)
+        .iter()
+        .any(|name| name == "*" || name.eq_ignore_ascii_case(coding))
+}
+
+/// The lowercased content-codings listed by the `Accept-Encoding` header,
+/// leaving out those rejected with a zero weight.
+fn accepted_codings(headers: &HeaderMap) -> HashSet<String> {
+    headers
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let name = parts.next().unwrap_or_default().trim();
+            let rejected = parts.any(|param| {
+                param
+                    .trim()
+                    .strip_prefix("q=")
+                    .and_then(|weight| weight.trim().parse::<f32>().ok())
+                    .is_some_and(|weight| weight == 0.0)
+            });
+            (!rejected && !name.is_empty()).then(|| name.to_ascii_lowercase())
+        })
+        .collect()
+}
+
+/// Whether the body of a stored response is still in the content-coding named by its
+/// `Content-Encoding` header. The fetch layer decodes the codings it supports before the
+/// body reaches the cache, after which the header no longer describes the stored bytes,
+/// which must then not be decoded again, as they may be an encoded file, such as a `.gz`.
+fn stored_body_is_encoded(headers: &HeaderMap) -> bool {
+    let mut codings = headers.get_all(header::CONTENT_ENCODING).iter().peekable();
+    codings.peek().is_some() && !codings.any(is_decoded_coding)
+}
+
+/// Decode bytes stored with a content-coding, if it is one we support.
+fn decode_content(coding: &str, bytes: &[u8]) -> Option<Vec<u8>> {
+    let mut decoded = vec![];
+    let result = match coding {
+        "gzip" | "x-gzip" => GzDecoder::new(bytes).read_to_end(&mut decoded),
+        "deflate" => ZlibDecoder::new(bytes).read_to_end(&mut decoded),
+        "br" => brotli::Decompressor::new(bytes, 4096).read_to_end(&mut decoded),
+        _ => return None,
+    };
+    result.ok().map(|_| decoded)
+}
+
+/// The content-coding of a stored response, if the request doesn't accept it.
+/// Resources whose `Vary` header lists `Accept-Encoding` are a separate variant,
+/// so their content-coding is never considered unaccepted.
+fn unaccepted_content_coding(request: &Request, headers: &HeaderMap) -> Option<String> {
+    if headers.typed_get::<Vary>().is_some_and(|vary| {
+        vary.iter_strs()
+            .any(|name| name.eq_ignore_ascii_case("accept-encoding"))
+    }) {
+        return None;
+    }
+    let coding = headers
+        .get(header::CONTENT_ENCODING)?
+        .to_str()
+        .ok()?
+        .trim()
+        .to_ascii_lowercase();
+    if request_accepts_coding(request, &coding) {
+        return None;
+    }
+    Some(coding)
+}
+
+/// Whether the body of a resource is still in a content-coding the request doesn't accept,
+/// and can't be decoded as it carries the no-transform directive. A body already decoded
+/// by the fetch layer is served as is.
+/// <https://tools.ietf.org/html/rfc7234#section-5.2.2.4>
+fn resource_forbids_transform(request: &Request, resource: &CachedResource) -> bool {
+    let headers = resource.lock_headers();
+    headers
+        .typed_get::<CacheControl>()
+        .is_some_and(|directives| directives.no_transform()) &&
+        stored_body_is_encoded(&headers) &&
+        unaccepted_content_coding(request, &headers).is_some()
+}
+
+/// Create a new resource with an identity-encoded body from an existing resource,
+/// if the request doesn't accept the content-coding its body is still stored in,
+/// and the response doesn't forbid transformations.
+fn create_decoded_resource(request: &Request, resource: &CachedResource) -> Option<CachedResource> {
+    let mut headers = resource.lock_headers().clone();
+    if !stored_body_is_encoded(&headers) {
+        return None;
+    }
+    let coding = unaccepted_content_coding(request, &headers)?;
+    if headers
+        .typed_get::<CacheControl>()
+        .is_some_and(|directives| directives.no_transform())
+    {
+        return None;
+    }
+    let decoded = match *resource.lock_body() {
+        ResponseBody::Done(ref bytes) => decode_content(&coding, bytes)?,
+        _ => return None,
+    };
+    headers.remove(header::CONTENT_ENCODING);
+    headers.remove(header::CONTENT_LENGTH);
+    let mut metadata = resource.metadata.clone();
+    metadata.headers = Arc::new(Mutex::new(headers));
+    Some(CachedResource {
+        body: Arc::new(Mutex::new(ResponseBody::Done(decoded))),
+        body_compressed: false,
+        body_hash: None,
+        body_dropped: false,
+        aborted: Arc::new(AtomicBool::new(false)),
+        awaiting_body: Arc::new(Mutex::new(vec![])),
+        producer: Arc::new(Mutex::new(BodyProducer::Original)),
+        outcome: Arc::new(Mutex::new(FetchOutcome::Completed)),
+        metadata,
+        ..resource.clone()
+    })
+}
+
+/// A hash of the bytes of a stored body, to verify its integrity.
+fn hash_body(bytes: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    bytes.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// The smallest body to compress when storing compressed bodies.
+const MIN_COMPRESSED_BODY_SIZE: usize = 1024;
+
+/// Compress a body to store, if it is worth it.
+fn compress_body(bytes: &[u8]) -> Option<Vec<u8>> {
+    if bytes.len() < MIN_COMPRESSED_BODY_SIZE {
+        return None;
+    }
+    let mut compressed = vec![];
+    DeflateEncoder::new(bytes, Compression::fast())
+        .read_to_end(&mut compressed)
+        .ok()?;
+    (compressed.len() < bytes.len()).then_some(compressed)
+}
+
+/// Compress the fully received body of a resource, and hash it, as configured.
+/// The body is shared with the responses being served, so a compressed one is
+/// stored separately.
+fn complete_stored_body(
+    cached_resource: &mut CachedResource,
+    compress_bodies: bool,
+    hash_bodies: bool,
+) {
+    let compressed_body = match *cached_resource.lock_body() {
+        ResponseBody::Done(ref bytes) if compress_bodies => compress_body(bytes),
+        _ => None,
+    };
+    if let Some(compressed_body) = compressed_body {
+        cached_resource.body = Arc::new(Mutex::new(ResponseBody::Done(compressed_body)));
+        cached_resource.body_compressed = true;
+    }
+    if hash_bodies {
+        let body_hash = match *cached_resource.lock_body() {
+            ResponseBody::Done(ref bytes) => Some(hash_body(bytes)),
+            _ => None,
+        };
+        cached_resource.body_hash = body_hash;
+    }
+}
+
+/// The body of a resource, as served, decompressing it if it is stored compressed.
+fn served_body(resource: &CachedResource) -> Arc<Mutex<ResponseBody>> {
+    if !resource.body_compressed {
+        return resource.body.clone();
+    }
+    let decompressed = match *resource.lock_body() {
+        ResponseBody::Done(ref bytes) => {
+            let mut decompressed = vec![];
+            match DeflateDecoder::new(&bytes[..]).read_to_end(&mut decompressed) {
+                Ok(_) => ResponseBody::Done(decompressed),
+                Err(error) => {
+                    warn!("failed to decompress a stored body: {error}");
+                    ResponseBody::Empty
+                },
+            }
+        },
+        ref body => body.clone(),
+    };
+    Arc::new(Mutex::new(decompressed))
+}
+
+/// Create a new resource with a decompressed body from an existing resource,
+/// if its body is stored compressed.
+fn create_decompressed_resource(resource: &CachedResource) -> Option<CachedResource> {
+    if !resource.body_compressed {
+        return None;
+    }
+    Some(CachedResource {
+        body: served_body(resource),
+        body_compressed: false,
+        body_hash: None,
+        body_dropped: false,
+        ..resource.clone()
+    })
+}
+
+/// Whether a cached resource has outlived its freshness lifetime,
+/// regardless of the request Cache-Control directives.
+fn resource_is_expired(cached_resource: &CachedResource, now: Instant) -> bool {
+    cached_resource.expires <= now - cached_resource.last_validated
+}
+
+/// Whether a cached resource is older than the maximum entry age, if any.
+fn resource_exceeds_max_entry_age(
+    cached_resource: &CachedResource,
+    now: Instant,
+    max_entry_age: Option<Duration>,
+) -> bool {
+    max_entry_age.is_some_and(|max_entry_age| now - cached_resource.last_validated > max_entry_age)
+}
+
+/// Whether a cached resource can be revalidated with a conditional request.
+fn resource_has_validators(cached_resource: &CachedResource) -> bool {
+    let cached_headers = cached_resource.lock_headers();
+    cached_headers.contains_key(header::ETAG) || cached_headers.contains_key(header::LAST_MODIFIED)
+}
+
+/// Whether the body of a complete 206 response has the length advertised by its
+/// Content-Range, so that range requests can safely be served from it.
+fn partial_body_matches_content_range(response: &Response) -> bool {
+    if response.status != StatusCode::PARTIAL_CONTENT {
+        return true;
+    }
+    let body_len = match *lock_unpoisoned(&response.body) {
+        ResponseBody::Done(ref body) => body.len() as u64,
+        // The length can only be checked once the body has been received.
+        ResponseBody::Receiving(_) | ResponseBody::Empty => return true,
+    };
+    let Some((beginning, end)) = response
+        .headers
+        .typed_get::<ContentRange>()
+        .and_then(|content_range| content_range.bytes_range())
+    else {
+        return false;
+    };
+    end.checked_sub(beginning)
+        .is_some_and(|range_len| range_len + 1 == body_len)
+}
+
+/// The indices, into a body starting at byte `offset` of a resource, delimiting the bytes
+/// from `beginning` to the inclusive `end`, unless they can't be represented as a `usize`.
+fn body_indices_of_byte_range(offset: u64, beginning: u64, end: u64) -> Option<(usize, usize)> {
+    let b = usize::try_from(beginning.checked_sub(offset)?).ok()?;
+    let e = usize::try_from(end.checked_sub(offset)?.checked_add(1)?).ok()?;
+    Some((b, e))
+}
+
+/// The size of the body of a resource, including a partially received one.
+fn resource_body_size(resource: &CachedResource) -> usize {
+    match *resource.lock_body() {
+        ResponseBody::Done(ref body) | ResponseBody::Receiving(ref body) => body.len(),
+        ResponseBody::Empty => 0,
+    }
+}
+
+/// The rank of a resource under an eviction policy, the lowest one being evicted first.
+fn eviction_rank(eviction_policy: EvictionPolicy, resource: &CachedResource) -> (u64, u64) {
+    let last_access = resource.last_access.load(Ordering::Relaxed);
+    match eviction_policy {
+        EvictionPolicy::Lru => (last_access, 0),
+        EvictionPolicy::Lfu => (resource.hit_count.load(Ordering::Relaxed), last_access),
+    }
+}
+
+impl HttpCache {
+    /// Create a cache, which requests the background revalidation of the stale responses
+    /// it serves under stale-while-revalidate on the given channel, if any.
+    /// The revalidation itself is left to the fetch layer.
+    pub fn new(revalidation_sender: Option<TokioSender<ServoUrl>>) -> HttpCache {
+        HttpCache {
+            revalidation_sender,
+            ..Default::default()
+        }
+    }
+
+    /// Set the callback notified whenever a resource is evicted, whether to fit the
+    /// memory budget, the variants cap, or when pruning expired resources.
+    pub fn set_on_evict(&mut self, on_evict: Option<EvictionCallback>) {
+        self.on_evict = on_evict;
+    }
+
+    /// Set the predicate which can veto the storage of responses the cache would otherwise
+    /// store, for example to apply rules specific to the embedder.
+    pub fn set_store_filter(&mut self, store_filter: Option<StoreFilter>) {
+        self.store_filter = store_filter;
+    }
+
+    /// Set how many ranges of complete resources recently served are kept ready to serve
+    /// repeated requests for the same range, for example when seeking in media, without
+    /// slicing their body again. No range is kept by default.
+    pub fn set_range_index_capacity(&mut self, capacity: usize) {
+        lock_unpoisoned(&self.range_index).set_capacity(capacity);
+    }
+
+    /// How many range requests were served from the ranges kept ready,
+    /// see `set_range_index_capacity`.
+    pub fn range_index_hits(&self) -> u64 {
+        lock_unpoisoned(&self.range_index).hits
+    }
+
+    /// Set the source of the current time used to compute freshness.
+    pub fn set_clock(&mut self, clock: Box<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// Set whether complete bodies are stored compressed, and decompressed when served,
+    /// so that more resources fit in the memory budget.
+    pub fn set_compress_bodies(&mut self, compress_bodies: bool) {
+        self.compress_bodies = compress_bodies;
+    }
+
+    /// Set whether to hash complete bodies when storing them, so that `verify_integrity`
+    /// can detect those which were since altered.
+    pub fn set_hash_bodies(&mut self, hash_bodies: bool) {
+        self.hash_bodies = hash_bodies;
+    }
+
+    /// Set whether responses to requests with a `Cookie` header, or with a `Set-Cookie`
+    /// header, are stored, which they are by default.
+    pub fn set_cache_with_cookies(&mut self, cache_with_cookies: bool) {
+        self.cache_with_cookies = cache_with_cookies;
+    }
+
+    /// Set the age, since they were stored or last validated, past which resources are
+    /// never used and are pruned, even if their headers say they're still fresh.
+    /// They aren't revalidated either, so that it can serve as a privacy ceiling on how
+    /// long anything is kept for ephemeral sessions.
+    pub fn set_max_entry_age(&mut self, max_entry_age: Option<Duration>) {
+        self.max_entry_age = max_entry_age;
+    }
+
+    /// Set the status codes to treat as cacheable by default, on top of those defined
+    /// as such, for example to heuristically cache 308 Permanent Redirects.
+    pub fn set_extra_cacheable_status(&mut self, extra_cacheable_status: HashSet<StatusCode>) {
+        self.extra_cacheable_status = extra_cacheable_status;
+    }
+
+    /// Set the maximum heuristic freshness lifetime of responses with a given status code,
+    /// overriding the default of 24 hours, for example to keep 301 Moved Permanently
+    /// redirects fresh for longer.
+    pub fn set_heuristic_caps(&mut self, heuristic_caps: HashMap<StatusCode, Duration>) {
+        self.heuristic_caps = heuristic_caps;
+    }
+
+    /// Set the bounds forced on the freshness lifetime of the responses stored or refreshed
+    /// from now on, whatever their headers say.
+    pub fn set_cache_control_override(
+        &mut self,
+        cache_control_override: Option<CacheControlOverride>,
+    ) {
+        self.cache_control_override = cache_control_override;
+    }
+
+    /// Set the freshness lifetime of stored responses lacking any freshness information,
+    /// instead of requiring their validation upon first use, or of using a heuristic
+    /// freshness when they don't carry a Last-Modified header either.
+    pub fn set_default_ttl(&mut self, default_ttl: Option<Duration>) {
+        self.default_ttl = default_ttl;
+    }
+
+    /// Set whether the cache is private to a single user, or shared between users.
+    pub fn set_mode(&mut self, mode: HttpCacheMode) {
+        self.mode = mode;
+    }
+
+    /// Set the maximum total size, in bytes, of the bodies stored in the cache,
+    /// evicting resources according to the eviction policy until it fits.
+    pub fn set_memory_budget(&mut self, memory_budget: Option<usize>) {
+        self.memory_budget = memory_budget;
+        self.evict_to_memory_budget();
+    }
+
+    /// Set the maximum size, in bytes, of a body to store.
+    /// Larger responses are not cached, so they can't evict the rest of the cache.
+    pub fn set_max_body_bytes(&mut self, max_body_bytes: Option<usize>) {
+        self.max_body_bytes = max_body_bytes;
+    }
+
+    /// Set the maximum number of variants stored under a single key,
+    /// so that a resource varying on a high-cardinality header can't grow without bounds.
+    pub fn set_max_variants_per_key(&mut self, max_variants_per_key: usize) {
+        self.max_variants_per_key = max_variants_per_key;
+    }
+
+    /// Set the maximum number of distinct keys stored in the cache, evicting all the
+    /// variants of the least recently accessed keys until it fits.
+    pub fn set_max_keys(&mut self, max_keys: Option<usize>) {
+        self.max_keys = max_keys;
+        if let Some(max_keys) = max_keys {
+            self.evict_keys_over(max_keys);
+        }
+    }
+
+    /// Set the pseudonym identifying the cache in the `Via` header of the responses it serves.
+    pub fn set_via_pseudonym(&mut self, via_pseudonym: String) {
+        self.via_pseudonym = via_pseudonym;
+    }
+
+    /// Set the policy used to pick which resources to evict when over the memory budget.
+    pub fn set_eviction_policy(&mut self, eviction_policy: EvictionPolicy) {
+        self.eviction_policy = eviction_policy;
+    }
+
+    /// Whether the body of a response, as announced by its Content-Length
+    /// or as received so far, is larger than the maximum size of a body to store.
+    fn body_exceeds_max_size(&self, response: &Response) -> bool {
+        let Some(max_body_bytes) = self.max_body_bytes else {
+            return false;
+        };
+        let content_length = response
+            .headers
+            .typed_get::<ContentLength>()
+            .map(|content_length| content_length.0);
+        if content_length.is_some_and(|content_length| content_length > max_body_bytes as u64) {
+            return true;
+        }
+        match *lock_unpoisoned(&response.body) {
+            ResponseBody::Done(ref body) | ResponseBody::Receiving(ref body) => {
+                body.len() > max_body_bytes
+            },
+            ResponseBody::Empty => false,
+        }
+    }
+
+    /// Evict resources until the stored bodies fit in the memory budget.
+    /// Resources whose body is still being received, or awaited, are never evicted.
+    fn evict_to_memory_budget(&mut self) {
+        let Some(memory_budget) = self.memory_budget else {
+            return;
+        };
+        let mut total_size: usize = self
+            .entries
+            .values()
+            .flatten()
+            .map(resource_body_size)
+            .sum();
+        // The indexed ranges are only copies of stored bodies, and are dropped first.
+        {
+            let mut range_index = lock_unpoisoned(&self.range_index);
+            total_size += range_index.body_size();
+            while total_size > memory_budget {
+                let Some(indexed) = range_index.ranges.pop_front() else {
+                    break;
+                };
+                total_size -= resource_body_size(&indexed.resource);
+            }
+        }
+        while total_size > memory_budget {
+            let eviction_policy = self.eviction_policy;
+            let victim = self
+                .entries
+                .iter()
+                .flat_map(|(entry_key, resources)| {
+                    resources
+                        .iter()
+                        .enumerate()
+                        .map(move |(index, resource)| (entry_key, index, resource))
+                })
+                .filter(|(_, _, resource)| {
+                    !has_awaiting_consumers(resource) &&
+                        !matches!(*resource.lock_body(), ResponseBody::Receiving(_))
+                })
+                .min_by_key(|(_, _, resource)| eviction_rank(eviction_policy, resource))
+                .map(|(entry_key, index, _)| (entry_key.clone(), index));
+            let Some((entry_key, index)) = victim else {
+                break;
+            };
+            let Some(resources) = self.entries.get_mut(&entry_key) else {
+                break;
+            };
+            let evicted = resources.remove(index);
+            if resources.is_empty() {
+                self.entries.remove(&entry_key);
+            }
+            notify_eviction(&self.on_evict, &self.range_index, &entry_key, &evicted);
+            total_size -= resource_body_size(&evicted);
+        }
+    }
+
+    /// Evict the least recently accessed keys, with all their variants, until there are
+    /// no more than `max_keys` of them. Keys with a body still being received, or awaited,
+    /// are never evicted.
+    fn evict_keys_over(&mut self, max_keys: usize) {
+        while self.entries.len() > max_keys {
+            let victim = self
+                .entries
+                .iter()
+                .filter(|(_, resources)| {
+                    !resources.iter().any(|resource| {
+                        has_awaiting_consumers(resource) ||
+                            matches!(*resource.lock_body(), ResponseBody::Receiving(_))
+                    })
+                })
+                .min_by_key(|(_, resources)| {
+                    resources
+                        .iter()
+                        .map(|resource| resource.last_access.load(Ordering::Relaxed))
+                        .max()
+                })
+                .map(|(entry_key, _)| entry_key.clone());
+            let Some(entry_key) = victim else {
+                break;
+            };
+            for evicted in self.entries.remove(&entry_key).unwrap_or_default() {
+                notify_eviction(&self.on_evict, &self.range_index, &entry_key, &evicted);
+            }
+        }
+    }
+
+    /// Whether a cached resource has outlived its freshness lifetime,
+    /// taking the request Cache-Control directives into account.
+    fn resource_needs_validation(
+        &self,
+        request: &Request,
+        cached_resource: &CachedResource,
+        cached_headers: &HeaderMap,
+    ) -> bool {
+        self.resource_freshness_remaining(request, cached_resource, cached_headers)
+            .is_zero()
+    }
+
+    /// How long a cached resource can still be used without validation,
+    /// taking the request Cache-Control directives into account.
+    fn resource_freshness_remaining(
+        &self,
+        request: &Request,
+        cached_resource: &CachedResource,
+        cached_headers: &HeaderMap,
+    ) -> Duration {
+        if request_forces_revalidation(request) {
+            return Duration::ZERO;
+        }
+        let expires = cached_resource.expires;
+        let adjusted_expires = get_expiry_adjustment_from_request_headers(request, expires);
+        let time_since_validated = self.clock.now_instant() - cached_resource.last_validated;
+
+        // A cache must not serve a stale response carrying must-revalidate, nor a shared
+        // one a stale response carrying proxy-revalidate, without validating it,
+        // even if the request accepts stale responses.
+        // <https://tools.ietf.org/html/rfc7234#section-5.2.2.1>
+        // <https://tools.ietf.org/html/rfc7234#section-5.2.2.7>
+        if resource_is_expired(cached_resource, self.clock.now_instant()) &&
+            (has_cache_control_directive(cached_headers, "must-revalidate") ||
+                (self.mode == HttpCacheMode::Shared &&
+                    has_cache_control_directive(cached_headers, "proxy-revalidate")))
+        {
+            return Duration::ZERO;
+        }
+
+        adjusted_expires.saturating_sub(time_since_validated)
+    }
+
+    /// Whether a stale resource is still within its stale-while-revalidate window,
+    /// and the request doesn't require a validated response. Only a resource stale
+    /// by its own age qualifies, not one the request directives want fresher.
+    /// Without a channel to request background revalidations on, nothing would
+    /// revalidate the resource, which then has to be validated before being served.
+    fn can_serve_while_revalidating(
+        &self,
+        request: &Request,
+        cached_resource: &CachedResource,
+        cached_headers: &HeaderMap,
+    ) -> bool {
+        if self.revalidation_sender.is_none() {
+            return false;
+        }
+        let Some(window) = stale_while_revalidate(cached_headers) else {
+            return false;
+        };
+        if request_forces_revalidation(request) ||
+            has_cache_control_directive(cached_headers, "must-revalidate")
+        {
+            return false;
+        }
+        if request
+            .headers
+            .typed_get::<CacheControl>()
+            .is_some_and(|directive| {
+                directive.max_age().is_some() || directive.min_fresh().is_some()
+            })
+        {
+            return false;
+        }
+        let time_since_validated = self.clock.now_instant() - cached_resource.last_validated;
+        time_since_validated >= cached_resource.expires &&
+            time_since_validated < cached_resource.expires + window
+    }
+
+    /// Ask the fetch layer to revalidate a resource in the background,
+    /// unless it has already been asked to since the resource was last validated.
+    fn request_background_revalidation(&self, request: &Request, cached_resource: &CachedResource) {
+        let Some(ref revalidation_sender) = self.revalidation_sender else {
+            return;
+        };
+        if cached_resource
+            .revalidation_requested
+            .swap(true, Ordering::AcqRel)
+        {
+            return;
+        }
+        let _ = revalidation_sender.send(request.current_url());
+    }
+
+    /// Create a CachedResponse from a request and a CachedResource.
+    fn create_cached_response(
+        &self,
+        request: &Request,
+        cached_resource: &CachedResource,
+        cached_headers: &HeaderMap,
+        done_chan: &mut DoneChannel,
+    ) -> Option<CachedResponse> {
+        debug!("creating a cached response for {:?}", request.url());
+        if cached_resource.aborted.load(Ordering::Acquire) ||
+            *lock_unpoisoned(&cached_resource.outcome) == FetchOutcome::Failed
+        {
+            return None;
+        }
+        let resource_timing = ResourceFetchTiming::new(request.timing_type());
+        let mut response =
+            Response::new(cached_resource.metadata.final_url.clone(), resource_timing);
+        response.headers = cached_headers.clone();
+        response
+            .headers
+            .extend(lock_unpoisoned(&cached_resource.metadata.trailers).clone());
+        append_via(&mut response.headers, &self.via_pseudonym);
+        response.body = served_body(cached_resource);
+        {
+            let mut awaiting_body = lock_unpoisoned(&cached_resource.awaiting_body);
+            if let ResponseBody::Receiving(_) = *cached_resource.lock_body() {
+                debug!("existing body is in progress");
+                let (done_sender, done_receiver) = unbounded();
+                *done_chan = Some((done_sender.clone(), done_receiver));
+                awaiting_body.push(done_sender);
+            }
+        }
+        response
+            .location_url
+            .clone_from(&cached_resource.location_url);
+        response.status.clone_from(&cached_resource.status);
+        response.url_list.clone_from(&cached_resource.url_list);
+        response.https_state = cached_resource.https_state;
+        response.referrer = request.referrer.to_url().cloned();
+        response.referrer_policy = request.referrer_policy;
+        response.aborted = cached_resource.aborted.clone();
+
+        let mut needs_validation =
+            self.resource_needs_validation(request, cached_resource, cached_headers);
+        if needs_validation &&
+            self.can_serve_while_revalidating(request, cached_resource, cached_headers)
+        {
+            needs_validation = false;
+            self.request_background_revalidation(request, cached_resource);
+        }
+        if !needs_validation {
+            // The fields named by a qualified no-cache directive can't be served
+            // without revalidation, but the rest of the response can.
+            for field in qualified_no_cache_fields(cached_headers) {
+                response.headers.remove(field.as_str());
+            }
+        }
+        cached_resource.hit_count.fetch_add(1, Ordering::Relaxed);
+        cached_resource
+            .last_access
+            .store(next_access_tick(), Ordering::Relaxed);
+        let cached_response = CachedResponse {
+            response,
+            needs_validation,
+            from_partial_merge: false,
+            content_type: cached_resource
+                .metadata
+                .content_type
+                .as_deref()
+                .and_then(|content_type| content_type.parse().ok()),
+            charset: cached_resource.metadata.charset.clone(),
+        };
+        Some(cached_response)
+    }
+
+    /// Support for range requests <https://tools.ietf.org/html/rfc7233>.
+    fn handle_range_request(
+        &self,
+        request: &Request,
+        candidates: &[&CachedResource],
+        range_spec: &Range,
+        done_chan: &mut DoneChannel,
+    ) -> Option<CachedResponse> {
+        let mut complete_cached_resources = candidates
+            .iter()
+            .filter(|resource| resource.status == StatusCode::OK);
+        // Partials are tried tightest first, so that the least bytes are copied
+        // when several of them cover the requested range.
+        let mut partial_cached_resources: Vec<_> = candidates
+            .iter()
+            .filter(|resource| resource.status == StatusCode::PARTIAL_CONTENT)
+            .collect();
+        partial_cached_resources.sort_by_key(|resource| {
+            resource
+                .lock_headers()
+                .typed_get::<ContentRange>()
+                .and_then(|range| range.bytes_range())
+                .map_or(u64::MAX, |(beginning, end)| end.saturating_sub(beginning))
+        });
+        if let Some(complete_resource) = complete_cached_resources.next() {
+            // TODO: take the full range spec into account.
+            // If we have a complete resource, take the request range from the body.
+            // When there isn't a complete resource available, we loop over cached partials,
+            // and see if any individual partial response can fulfill the current request for a bytes range.
+            // TODO: combine partials that in combination could satisfy the requested range?
+            // see <https://tools.ietf.org/html/rfc7233#section-4.3>.
+            // TODO: add support for partial resources whose body is in the
+            // ResponseBody::Receiving state.
+            let entry_key = CacheKey::new(request);
+            let is_complete = matches!(*complete_resource.lock_body(), ResponseBody::Done(_));
+            // A range served before is looked up before decompressing the stored body.
+            let indexed = if is_complete {
+                lock_unpoisoned(&self.range_index).get(&entry_key, range_spec, complete_resource)
+            } else {
+                None
+            };
+            if let Some(indexed) = indexed {
+                let cached_headers = range_response_headers(&indexed.lock_headers());
+                return self.create_cached_response(request, &indexed, &cached_headers, done_chan);
+            }
+            let decompressed_resource = create_decompressed_resource(complete_resource);
+            let complete_resource = decompressed_resource.as_ref().unwrap_or(complete_resource);
+            // A complete resource whose body is still being received can only serve
+            // the bounded ranges which lie within the bytes already received,
+            // since its full length isn't known yet.
+            let body_len = match *complete_resource.lock_body() {
+                ResponseBody::Done(ref body) | ResponseBody::Receiving(ref body) => body.len(),
+                ResponseBody::Empty => 0,
+            };
+            let body_len = u64::try_from(body_len).ok()?;
+            let bound = range_spec.satisfiable_ranges(body_len).next()?;
+            if is_complete &&
+                range_spec.satisfiable_ranges(body_len).all(
+                    |(start, _)| matches!(start, Bound::Included(start) if start >= body_len),
+                )
+            {
+                // None of the ranges start within the body, and we know its true length.
+                // <https://tools.ietf.org/html/rfc7233#section-4.4>
+                let mut new_resource =
+                    create_resource_with_bytes_from_resource(vec![], complete_resource);
+                new_resource.status = StatusCode::RANGE_NOT_SATISFIABLE.into();
+                let mut cached_headers = complete_resource.lock_headers().clone();
+                cached_headers.remove(header::CONTENT_LENGTH);
+                cached_headers.typed_insert(ContentRange::unsatisfied_bytes(body_len));
+                return self.create_cached_response(
+                    request,
+                    &new_resource,
+                    &cached_headers,
+                    done_chan,
+                );
+            }
+            let requested = match bound {
+                (Bound::Included(beginning), Bound::Included(end)) => {
+                    let (b, e) = body_indices_of_byte_range(0, beginning, end)?;
+                    // The requested bytes are copied before creating the new resource,
+                    // whose metadata is shared with the stored one, and mustn't be locked
+                    // while its body is.
+                    match *complete_resource.lock_body() {
+                        ResponseBody::Done(ref body) | ResponseBody::Receiving(ref body) => {
+                            body.get(b..e).map(<[u8]>::to_vec)
+                        },
+                        ResponseBody::Empty => None,
+                    }
+                },
+                (Bound::Included(beginning), Bound::Unbounded) => {
+                    let b = usize::try_from(beginning).ok()?;
+                    match *complete_resource.lock_body() {
+                        ResponseBody::Done(ref body) => body.get(b..).map(<[u8]>::to_vec),
+                        _ => None,
+                    }
+                },
+                _ => return None,
+            };
+            let new_resource =
+                create_resource_with_bytes_from_resource(requested?, complete_resource);
+            if is_complete {
+                lock_unpoisoned(&self.range_index).insert(
+                    &entry_key,
+                    range_spec,
+                    complete_resource,
+                    &new_resource,
+                );
+            }
+            let cached_headers = range_response_headers(&new_resource.lock_headers());
+            return self.create_cached_response(request, &new_resource, &cached_headers, done_chan);
+        } else {
+            for partial_resource in partial_cached_resources {
+                let decompressed_resource = create_decompressed_resource(partial_resource);
+                let partial_resource = decompressed_resource.as_ref().unwrap_or(partial_resource);
+                let headers = partial_resource.lock_headers();
+                let content_range = headers.typed_get::<ContentRange>();
+
+                let last_byte = match content_range.as_ref().map(|range| range.bytes_len()) {
+                    Some(Some(complete_length)) => complete_length.checked_sub(1),
+                    // The complete length is unknown, as in `bytes 0-499/*`, but the bytes
+                    // received still satisfy the bounded ranges lying within them. Suffix
+                    // ranges, which can't be resolved without it, start past those bytes.
+                    Some(None) => Some(u64::MAX),
+                    None => None,
+                };
+                let Some(last_byte) = last_byte else {
+                    continue;
+                };
+                let Some(bound) = range_spec.satisfiable_ranges(last_byte).next() else {
+                    continue;
+                };
+                match bound {
+                    (Bound::Included(beginning), Bound::Included(end)) => {
+                        let (res_beginning, res_end) = match content_range {
+                            Some(range) => {
+                                if let Some(bytes_range) = range.bytes_range() {
+                                    bytes_range
+                                } else {
+                                    continue;
+                                }
                             },
                             _ => continue,
                         };
-                        if let Some(bytes) = requested {
-                            let new_resource =
-                                create_resource_with_bytes_from_resource(bytes, partial_resource);
-                            let cached_response =
-                                create_cached_response(request, &new_resource, &headers, done_chan);
+                        let is_complete =
+                            matches!(*partial_resource.lock_body(), ResponseBody::Done(_));
+                        if is_complete && res_beginning == beginning && res_end == end {
+                            // The stored partial is exactly the requested range,
+                            // serve it without copying its body.
+                            let cached_response = self.create_cached_response(
+                                request,
+                                partial_resource,
+                                &headers,
+                                done_chan,
+                            );
                             if let Some(cached_response) = cached_response {
                                 return Some(cached_response);
                             }
                         }
-                    }
-                },
+                        if res_beginning <= beginning && res_end >= end {
+                            let Some((b, e)) =
+                                body_indices_of_byte_range(res_beginning, beginning, end)
+                            else {
+                                continue;
+                            };
+                            let requested = match *partial_resource.lock_body() {
+                                ResponseBody::Done(ref body) => body.get(b..e).map(<[u8]>::to_vec),
+                                _ => continue,
+                            };
+                            if let Some(bytes) = requested {
+                                let new_resource = create_resource_with_bytes_from_resource(
+                                    bytes,
+                                    partial_resource,
+                                );
+                                let cached_response = self.create_cached_response(
+                                    request,
+                                    &new_resource,
+                                    &range_response_headers(&headers),
+                                    done_chan,
+                                );
+                                if let Some(cached_response) = cached_response {
+                                    return Some(cached_response);
+                                }
+                            }
+                        }
+                    },
 
-                _ => continue,
+                    (Bound::Included(beginning), Bound::Unbounded) => {
+                        let (res_beginning, res_end, total) = if let Some(range) = content_range {
+                            match (range.bytes_range(), range.bytes_len()) {
+                                (Some(bytes_range), Some(total)) => {
+                                    (bytes_range.0, bytes_range.1, total)
+                                },
+                                _ => continue,
+                            }
+                        } else {
+                            continue;
+                        };
+                        if total == 0 {
+                            // Prevent overflow in the below operations from occuring.
+                            continue;
+                        };
+                        if res_beginning <= beginning && res_end == total - 1 {
+                            let Some(from_byte) = beginning
+                                .checked_sub(res_beginning)
+                                .and_then(|from_byte| usize::try_from(from_byte).ok())
+                            else {
+                                continue;
+                            };
+                            let requested = match *partial_resource.lock_body() {
+                                ResponseBody::Done(ref body) => {
+                                    body.get(from_byte..).map(<[u8]>::to_vec)
+                                },
+                                _ => continue,
+                            };
+                            if let Some(bytes) = requested {
+                                let new_resource = create_resource_with_bytes_from_resource(
+                                    bytes,
+                                    partial_resource,
+                                );
+                                let cached_response = self.create_cached_response(
+                                    request,
+                                    &new_resource,
+                                    &range_response_headers(&headers),
+                                    done_chan,
+                                );
+                                if let Some(cached_response) = cached_response {
+                                    return Some(cached_response);
+                                }
+                            }
+                        }
+                    },
+
+                    _ => continue,
+                }
             }
         }
-    }
 
-    None
-}
+        None
+    }
 
-impl HttpCache {
     /// Constructing Responses from Caches.
     /// <https://tools.ietf.org/html/rfc7234#section-4>
+    ///
+    /// When no response can be constructed, `explain` tells why, for example whether
+    /// nothing was stored for the url, or whether all stored resources were aborted.
+    /// Concurrent fetches missing the cache for the same url are coalesced by the fetch
+    /// layer, which waits on `HttpCacheEntryState::PendingStore` for the first one to store.
     pub fn construct_response(
         &self,
         request: &Request,
@@ -520,64 +2325,47 @@ impl HttpCache {
             debug!("non-GET method, not caching");
             return None;
         }
+        if request
+            .headers
+            .typed_get::<CacheControl>()
+            .is_some_and(|directive| directive.no_store())
+        {
+            // The request must not be satisfied from the cache.
+            // <https://tools.ietf.org/html/rfc7234#section-5.2.1.5>
+            debug!("request has no-store, not using cache");
+            return None;
+        }
+        if cache_mode_bypasses_cache(request) {
+            debug!("request cache mode bypasses the cache");
+            return None;
+        }
         let entry_key = CacheKey::new(request);
+        let now = self.clock.now_instant();
         let resources = self
             .entries
             .get(&entry_key)?
             .iter()
-            .filter(|r| !r.aborted.load(Ordering::Relaxed));
-        let mut candidates = vec![];
-        for cached_resource in resources {
-            let mut can_be_constructed = true;
-            let cached_headers = cached_resource.metadata.headers.lock().unwrap();
-            let original_request_headers = cached_resource.request_headers.lock().unwrap();
-            if let Some(vary_value) = cached_headers.typed_get::<Vary>() {
-                if vary_value.is_any() {
-                    debug!("vary value is any, not caching");
-                    can_be_constructed = false
-                } else {
-                    // For every header name found in the Vary header of the stored response.
-                    // Calculating Secondary Keys with Vary <https://tools.ietf.org/html/rfc7234#section-4.1>
-                    for vary_val in vary_value.iter_strs() {
-                        match request.headers.get(vary_val) {
-                            Some(header_data) => {
-                                // If the header is present in the request.
-                                if let Some(original_header_data) =
-                                    original_request_headers.get(vary_val)
-                                {
-                                    // Check that the value of the nominated header field,
-                                    // in the original request, matches the value in the current request.
-                                    if original_header_data != header_data {
-                                        debug!("headers don't match, not caching");
-                                        can_be_constructed = false;
-                                        break;
-                                    }
-                                }
-                            },
-                            None => {
-                                // If a header field is absent from a request,
-                                // it can only match a stored response if those headers,
-                                // were also absent in the original request.
-                                can_be_constructed =
-                                    original_request_headers.get(vary_val).is_none();
-                                if !can_be_constructed {
-                                    debug!("vary header present, not caching");
-                                }
-                            },
-                        }
-                        if !can_be_constructed {
-                            break;
-                        }
-                    }
-                }
-            }
-            if can_be_constructed {
-                candidates.push(cached_resource);
-            }
-        }
+            .filter(|r| !r.aborted.load(Ordering::Relaxed))
+            .filter(|r| !resource_exceeds_max_entry_age(r, now, self.max_entry_age))
+            // A stub whose body was dropped can only be used to revalidate.
+            .filter(|r| !r.body_dropped)
+            // A resource left inconsistent by a panic is treated as evicted.
+            .filter(|r| !r.is_poisoned())
+            // A resource stored for a Head request has no body to answer a Get one with.
+            .filter(|r| r.method == request.method);
+        // Bodies stored compressed are decompressed before being sliced, decoded, or served,
+        // only for the candidate actually used.
+        let mut candidates: Vec<&CachedResource> = resources
+            .filter(|cached_resource| vary_matches(request, cached_resource))
+            .collect();
         // Support for range requests
         if let Some(range_spec) = request.headers.typed_get::<Range>() {
-            return handle_range_request(request, candidates.as_slice(), &range_spec, done_chan);
+            return self.handle_range_request(
+                request,
+                candidates.as_slice(),
+                &range_spec,
+                done_chan,
+            );
         }
         while let Some(cached_resource) = candidates.pop() {
             // Not a Range request.
@@ -599,13 +2387,37 @@ impl HttpCache {
                 },
                 None => continue,
             }
+            // Serve an identity-encoded body to requests that don't accept the stored coding,
+            // unless the response can't be transformed, in which case it can't be served.
+            if resource_forbids_transform(request, cached_resource) {
+                continue;
+            }
+            let decompressed_resource = create_decompressed_resource(cached_resource);
+            let cached_resource = decompressed_resource.as_ref().unwrap_or(cached_resource);
+            let decoded_resource = create_decoded_resource(request, cached_resource);
+            let cached_resource = decoded_resource.as_ref().unwrap_or(cached_resource);
             // Returning a response that can be constructed
             // TODO: select the most appropriate one, using a known mechanism from a selecting header field,
             // or using the Date header to return the most recent one.
-            let cached_headers = cached_resource.metadata.headers.lock().unwrap();
+            let cached_headers = cached_resource.lock_headers();
             let cached_response =
-                create_cached_response(request, cached_resource, &cached_headers, done_chan);
-            if let Some(cached_response) = cached_response {
+                self.create_cached_response(request, cached_resource, &cached_headers, done_chan);
+            if let Some(mut cached_response) = cached_response {
+                // A conditional request whose validators match those of a fresh resource
+                // is answered with a 304, without going to the network. Fetch only consults
+                // the cache for those in the "force-cache" and "only-if-cached" modes.
+                // <https://tools.ietf.org/html/rfc7234#section-4.3.2>
+                if !cached_response.needs_validation &&
+                    request_validators_match(request, &cached_headers)
+                {
+                    // There is no body to wait for.
+                    if let Some((done_sender, _)) = done_chan.take() {
+                        lock_unpoisoned(&cached_resource.awaiting_body)
+                            .retain(|awaiting| !awaiting.same_channel(&done_sender));
+                    }
+                    cached_response.response.status = StatusCode::NOT_MODIFIED.into();
+                    cached_response.response.body = Arc::new(Mutex::new(ResponseBody::Empty));
+                }
                 return Some(cached_response);
             }
         }
@@ -614,55 +2426,373 @@ impl HttpCache {
         None
     }
 
+    /// Explain whether, and why, a request would be answered from the cache,
+    /// following the steps of `construct_response` without constructing a response,
+    /// nor updating the statistics of the resources.
+    pub fn explain(&self, request: &Request) -> CacheLookupExplanation {
+        if request.method != Method::GET {
+            return CacheLookupExplanation::NotGet;
+        }
+        if request
+            .headers
+            .typed_get::<CacheControl>()
+            .is_some_and(|directive| directive.no_store())
+        {
+            return CacheLookupExplanation::NoStoreRequest;
+        }
+        if cache_mode_bypasses_cache(request) {
+            return CacheLookupExplanation::BypassedByCacheMode;
+        }
+        let entry_key = CacheKey::new(request);
+        let now = self.clock.now_instant();
+        let resources: Vec<&CachedResource> = self
+            .entries
+            .get(&entry_key)
+            .into_iter()
+            .flatten()
+            .filter(|resource| !resource_exceeds_max_entry_age(resource, now, self.max_entry_age))
+            .filter(|resource| !resource.body_dropped)
+            .filter(|resource| !resource.is_poisoned())
+            .filter(|resource| resource.method == request.method)
+            .collect();
+        if resources.is_empty() {
+            return CacheLookupExplanation::NoEntry;
+        }
+        let live_resources: Vec<&CachedResource> = resources
+            .into_iter()
+            .filter(|resource| {
+                !resource.aborted.load(Ordering::Relaxed) &&
+                    *lock_unpoisoned(&resource.outcome) != FetchOutcome::Failed
+            })
+            .collect();
+        if live_resources.is_empty() {
+            return CacheLookupExplanation::Aborted;
+        }
+        let mut mismatched_header = None;
+        let mut candidates = vec![];
+        for resource in live_resources {
+            match vary_mismatch(request, resource) {
+                Some(header) => mismatched_header = Some(header),
+                None => candidates.push(resource),
+            }
+        }
+        if candidates.is_empty() {
+            return CacheLookupExplanation::VaryMismatch(mismatched_header.unwrap_or_default());
+        }
+        if !request.headers.contains_key(header::RANGE) {
+            candidates.retain(|resource| {
+                resource.status != StatusCode::PARTIAL_CONTENT &&
+                    resource.status.try_code().is_some()
+            });
+            if candidates.is_empty() {
+                return CacheLookupExplanation::OnlyPartialContent;
+            }
+            candidates.retain(|resource| !resource_forbids_transform(request, resource));
+            if candidates.is_empty() {
+                return CacheLookupExplanation::NoTransform;
+            }
+        }
+        let Some(cached_resource) = candidates.last() else {
+            return CacheLookupExplanation::OnlyPartialContent;
+        };
+        let cached_headers = cached_resource.lock_headers();
+        if self.resource_needs_validation(request, cached_resource, &cached_headers) &&
+            !self.can_serve_while_revalidating(request, cached_resource, &cached_headers)
+        {
+            return CacheLookupExplanation::NeedsValidation;
+        }
+        CacheLookupExplanation::Hit
+    }
+
+    /// Build the validators for a conditional request revalidating the stored response
+    /// matching `request`, as per <https://tools.ietf.org/html/rfc7234#section-4.3.1>.
+    ///
+    /// The entity-tags of the other variants stored for the url are listed too, so that
+    /// the server can select any of them with its 304 response.
+    ///
+    /// Returns `None` if no stale matching resource is stored, or if it carries
+    /// neither an `ETag` nor a `Last-Modified` header.
+    pub fn conditional_headers_for(&self, request: &Request) -> Option<HeaderMap> {
+        let entry_key = CacheKey::new(request);
+        let now = self.clock.now_instant();
+        let cached_resources = self.entries.get(&entry_key)?;
+        let cached_resource = cached_resources
+            .iter()
+            .filter(|r| r.method == request.method && !r.aborted.load(Ordering::Relaxed))
+            .filter(|r| !resource_exceeds_max_entry_age(r, now, self.max_entry_age))
+            .filter(|r| !r.is_poisoned())
+            .filter(|r| vary_matches(request, r))
+            .filter(|r| {
+                let cached_headers = r.lock_headers();
+                r.body_dropped || self.resource_needs_validation(request, r, &cached_headers)
+            })
+            // Among several stale variants, revalidate the most recently validated one.
+            .max_by_key(|r| r.last_validated)?;
+
+        // The entity-tag of the variant to revalidate comes first.
+        let mut entity_tags: Vec<HeaderValue> = vec![];
+        let variants = std::iter::once(cached_resource).chain(
+            cached_resources
+                .iter()
+                .filter(|r| r.method == request.method && !r.aborted.load(Ordering::Relaxed))
+                .filter(|r| !resource_exceeds_max_entry_age(r, now, self.max_entry_age))
+                .filter(|r| !r.is_poisoned()),
+        );
+        for variant in variants {
+            if let Some(entity_tag) = variant.lock_headers().get(header::ETAG) {
+                if !entity_tags.contains(entity_tag) {
+                    entity_tags.push(entity_tag.clone());
+                }
+            }
+        }
+        let cached_headers = cached_resource.lock_headers();
+        let mut conditional_headers = HeaderMap::new();
+        let entity_tags: Vec<&str> = entity_tags
+            .iter()
+            .filter_map(|entity_tag| entity_tag.to_str().ok())
+            .collect();
+        if let Ok(if_none_match) = HeaderValue::from_str(&entity_tags.join(", ")) {
+            if !if_none_match.is_empty() {
+                conditional_headers.insert(header::IF_NONE_MATCH, if_none_match);
+            }
+        }
+        // Send back the HTTP-date of a valid Last-Modified header as it was received,
+        // as some servers compare it to their own verbatim.
+        // <https://tools.ietf.org/html/rfc7232#section-3.3>
+        if cached_headers.typed_get::<LastModified>().is_some() {
+            if let Some(last_modified) = cached_headers.get(header::LAST_MODIFIED) {
+                conditional_headers.insert(header::IF_MODIFIED_SINCE, last_modified.clone());
+            }
+        }
+        if conditional_headers.is_empty() {
+            return None;
+        }
+        Some(conditional_headers)
+    }
+
     /// Wake-up consumers of cached resources
     /// whose response body was still receiving data when the resource was constructed,
     /// and whose response has now either been completed or cancelled.
-    pub fn update_awaiting_consumers(&self, request: &Request, response: &Response) {
+    pub fn update_awaiting_consumers(&mut self, request: &Request, response: &Response) {
         let entry_key = CacheKey::new(request);
+        self.complete_received_bodies(&entry_key);
+        self.wake_awaiting_consumers(&entry_key, response);
+    }
 
-        let cached_resources = match self.entries.get(&entry_key) {
+    fn wake_awaiting_consumers(&self, entry_key: &CacheKey, response: &Response) {
+        let cached_resources = match self.entries.get(entry_key) {
             None => return,
             Some(resources) => resources,
         };
 
         let actual_response = response.actual_response();
 
-        // Ensure we only wake-up consumers of relevant resources,
-        // ie we don't want to wake-up 200 awaiting consumers with a 206.
-        let relevant_cached_resources = cached_resources.iter().filter(|resource| {
+        for cached_resource in cached_resources.iter() {
+            let mut awaiting_consumers = lock_unpoisoned(&cached_resource.awaiting_body);
+            if awaiting_consumers.is_empty() {
+                continue;
+            }
+            let mut producer = lock_unpoisoned(&cached_resource.producer);
+            if *producer == BodyProducer::PromotedConsumer {
+                // The consumers are parked on the fetch of a promoted consumer,
+                // whose response can't be told apart from that of another fetch
+                // for the same resource completing here.
+                let producer_failed = actual_response.is_network_error() ||
+                    actual_response.aborted.load(Ordering::Acquire);
+                if producer_failed {
+                    promote_next_consumer(&mut awaiting_consumers);
+                    continue;
+                }
+                let completed_body = match *lock_unpoisoned(&actual_response.body) {
+                    ResponseBody::Done(ref completed_body)
+                        if cached_resource.status == actual_response.status =>
+                    {
+                        Some(completed_body.clone())
+                    },
+                    _ => None,
+                };
+                let Some(completed_body) = completed_body else {
+                    // The response can't provide the body of the resource,
+                    // the parked consumers make their own network requests
+                    // rather than waiting on a fetch which may never complete it.
+                    for done_sender in awaiting_consumers.drain(..) {
+                        let _ = done_sender.send(Data::Cancelled);
+                    }
+                    continue;
+                };
+                *cached_resource.lock_body() = ResponseBody::Done(completed_body);
+                for done_sender in awaiting_consumers.drain(..) {
+                    let _ = done_sender.send(Data::Done);
+                }
+                continue;
+            }
+
+            let mut outcome = lock_unpoisoned(&cached_resource.outcome);
+            // Ensure we only wake-up consumers of relevant resources,
+            // ie we don't want to wake-up 200 awaiting consumers with a 206,
+            // or the consumers of a completed resource with a network error.
             if actual_response.is_network_error() {
-                return *resource.body.lock().unwrap() == ResponseBody::Empty;
+                if *outcome == FetchOutcome::Completed ||
+                    *cached_resource.lock_body() != ResponseBody::Empty
+                {
+                    continue;
+                }
+            } else if cached_resource.status != actual_response.status {
+                continue;
             }
-            resource.status == actual_response.status
-        });
 
-        for cached_resource in relevant_cached_resources {
-            let mut awaiting_consumers = cached_resource.awaiting_body.lock().unwrap();
-            if awaiting_consumers.is_empty() {
+            if cached_resource.aborted.load(Ordering::Acquire) || actual_response.is_network_error()
+            {
+                // In the case of a failed fetch, wake-up only one consumer,
+                // which will start a new network request and become the producer
+                // on which the others wait.
+                *outcome = FetchOutcome::Failed;
+                *producer = BodyProducer::PromotedConsumer;
+                promote_next_consumer(&mut awaiting_consumers);
                 continue;
             }
-            let to_send = if cached_resource.aborted.load(Ordering::Acquire) {
-                // In the case of an aborted fetch,
-                // wake-up all awaiting consumers.
-                // Each will then start a new network request.
-                // TODO: Wake-up only one consumer, and make it the producer on which others wait.
-                Data::Cancelled
-            } else {
-                match *cached_resource.body.lock().unwrap() {
-                    ResponseBody::Done(_) | ResponseBody::Empty => Data::Done,
-                    ResponseBody::Receiving(_) => {
-                        continue;
-                    },
+            if let ResponseBody::Receiving(_) = *cached_resource.lock_body() {
+                continue;
+            }
+            *outcome = FetchOutcome::Completed;
+            for done_sender in awaiting_consumers.drain(..) {
+                let _ = done_sender.send(Data::Done);
+            }
+        }
+    }
+
+    /// Finalize the body of a resource whose fetch completed elsewhere in the fetch pipeline,
+    /// so that subsequent lookups, including range requests, see the complete body.
+    /// Consumers awaiting the body are woken-up.
+    pub fn finalize_body(&mut self, request: &Request, body: Vec<u8>) {
+        let entry_key = CacheKey::new(request);
+        let Some(cached_resources) = self.entries.get(&entry_key) else {
+            return;
+        };
+        let Some(cached_resource) = cached_resources.iter().rev().find(|cached_resource| {
+            cached_resource.method == request.method &&
+                !cached_resource.is_poisoned() &&
+                vary_matches(request, cached_resource) &&
+                matches!(*cached_resource.lock_body(), ResponseBody::Receiving(_))
+        }) else {
+            return;
+        };
+        *cached_resource.lock_body() = ResponseBody::Done(body);
+        self.complete_received_bodies(&entry_key);
+        self.evict_to_memory_budget();
+    }
+
+    /// Complete the resources stored for a key whose body has now been fully received,
+    /// waking-up the consumers awaiting it. Resources whose body is larger than the maximum
+    /// size of a body to store are removed, as that of a response without a Content-Length
+    /// is unknown when it is stored. The others have their body compressed and hashed,
+    /// as configured.
+    fn complete_received_bodies(&mut self, entry_key: &CacheKey) {
+        let Some(cached_resources) = self.entries.get_mut(entry_key) else {
+            return;
+        };
+        let (max_body_bytes, compress_bodies, hash_bodies) =
+            (self.max_body_bytes, self.compress_bodies, self.hash_bodies);
+        let on_evict = &self.on_evict;
+        let range_index = &self.range_index;
+        cached_resources.retain_mut(|cached_resource| {
+            if cached_resource.aborted.load(Ordering::Acquire) {
+                return true;
+            }
+            let body_len = {
+                let mut awaiting_consumers = lock_unpoisoned(&cached_resource.awaiting_body);
+                let mut outcome = lock_unpoisoned(&cached_resource.outcome);
+                if *outcome != FetchOutcome::Pending {
+                    return true;
+                }
+                let body_len = match *cached_resource.lock_body() {
+                    ResponseBody::Done(ref body) => body.len(),
+                    _ => return true,
+                };
+                *outcome = FetchOutcome::Completed;
+                for done_sender in awaiting_consumers.drain(..) {
+                    let _ = done_sender.send(Data::Done);
                 }
+                body_len
             };
-            for done_sender in awaiting_consumers.drain(..) {
-                let _ = done_sender.send(to_send.clone());
+            if max_body_bytes.is_some_and(|max_body_bytes| body_len > max_body_bytes) {
+                notify_eviction(on_evict, range_index, entry_key, cached_resource);
+                return false;
             }
+            complete_stored_body(cached_resource, compress_bodies, hash_bodies);
+            true
+        });
+        if cached_resources.is_empty() {
+            self.entries.remove(entry_key);
         }
     }
 
+    /// Remove the expired resources which can't be revalidated, as they have no validators,
+    /// those older than the maximum entry age, the stubs whose body was dropped, and the
+    /// resources left inconsistent by a panic, returning how many were removed. Other
+    /// resources whose body is still being received, or awaited, are kept.
+    pub fn prune_expired(&mut self) -> usize {
+        let now = self.clock.now_instant();
+        let mut pruned = 0;
+        let max_entry_age = self.max_entry_age;
+        let on_evict = &self.on_evict;
+        let range_index = &self.range_index;
+        self.entries.retain(|entry_key, cached_resources| {
+            cached_resources.retain(|cached_resource| {
+                // A resource left inconsistent by a panic is removed even while its body
+                // is awaited, its consumers being woken-up to make their own requests.
+                if !cached_resource.is_poisoned() {
+                    if has_awaiting_consumers(cached_resource) {
+                        return true;
+                    }
+                    if let ResponseBody::Receiving(_) = *cached_resource.lock_body() {
+                        return true;
+                    }
+                    let unusable = cached_resource.body_dropped ||
+                        resource_exceeds_max_entry_age(cached_resource, now, max_entry_age) ||
+                        (resource_is_expired(cached_resource, now) &&
+                            !resource_has_validators(cached_resource));
+                    if !unusable {
+                        return true;
+                    }
+                }
+                notify_eviction(on_evict, range_index, entry_key, cached_resource);
+                pruned += 1;
+                false
+            });
+            !cached_resources.is_empty()
+        });
+        pruned
+    }
+
+    /// How long the resource which would be used to answer a request can still be used
+    /// without validation, or `None` if there is no such resource.
+    pub fn freshness_remaining(&self, request: &Request) -> Option<Duration> {
+        let entry_key = CacheKey::new(request);
+        let cached_resource = self
+            .entries
+            .get(&entry_key)?
+            .iter()
+            .rev()
+            .find(|resource| {
+                !resource.aborted.load(Ordering::Relaxed) &&
+                    !resource.is_poisoned() &&
+                    resource.method == request.method &&
+                    *lock_unpoisoned(&resource.outcome) != FetchOutcome::Failed &&
+                    resource.status != StatusCode::PARTIAL_CONTENT &&
+                    resource.status.try_code().is_some() &&
+                    vary_matches(request, resource)
+            })?;
+        let cached_headers = cached_resource.lock_headers();
+        Some(self.resource_freshness_remaining(request, cached_resource, &cached_headers))
+    }
+
     /// Freshening Stored Responses upon Validation.
     /// <https://tools.ietf.org/html/rfc7234#section-4.3.4>
+    ///
+    /// A stub whose body was dropped has nothing to construct a response with: when the
+    /// 304 response selects one, it is removed and `None` is returned, so that the caller
+    /// fetches the resource again, this time without validators.
     pub fn refresh(
         &mut self,
         request: &Request,
@@ -671,54 +2801,153 @@ impl HttpCache {
     ) -> Option<Response> {
         assert_eq!(response.status, StatusCode::NOT_MODIFIED);
         let entry_key = CacheKey::new(request);
-        if let Some(cached_resources) = self.entries.get_mut(&entry_key) {
-            if let Some(cached_resource) = cached_resources.iter_mut().next() {
-                // done_chan will have been set to Some(..) by http_network_fetch.
-                // If the body is not receiving data, set the done_chan back to None.
-                // Otherwise, create a new dedicated channel to update the consumer.
-                // The response constructed here will replace the 304 one from the network.
-                let in_progress_channel = match *cached_resource.body.lock().unwrap() {
-                    ResponseBody::Receiving(..) => Some(unbounded()),
-                    ResponseBody::Empty | ResponseBody::Done(..) => None,
-                };
-                match in_progress_channel {
-                    Some((done_sender, done_receiver)) => {
-                        *done_chan = Some((done_sender.clone(), done_receiver));
-                        cached_resource
-                            .awaiting_body
-                            .lock()
-                            .unwrap()
-                            .push(done_sender);
-                    },
-                    None => *done_chan = None,
-                }
-                // Received a response with 304 status code, in response to a request that matches a cached resource.
-                // 1. update the headers of the cached resource.
-                // 2. return a response, constructed from the cached resource.
-                let resource_timing = ResourceFetchTiming::new(request.timing_type());
-                let mut constructed_response =
-                    Response::new(cached_resource.metadata.final_url.clone(), resource_timing);
-                constructed_response.body = cached_resource.body.clone();
-                constructed_response
-                    .status
-                    .clone_from(&cached_resource.status);
-                constructed_response.https_state = cached_resource.https_state;
-                constructed_response.referrer = request.referrer.to_url().cloned();
-                constructed_response.referrer_policy = request.referrer_policy;
-                constructed_response
-                    .status
-                    .clone_from(&cached_resource.status);
-                constructed_response
-                    .url_list
-                    .clone_from(&cached_resource.url_list);
-                cached_resource.expires = get_response_expiry(&constructed_response);
-                let mut stored_headers = cached_resource.metadata.headers.lock().unwrap();
-                stored_headers.extend(response.headers);
-                constructed_response.headers = stored_headers.clone();
-                return Some(constructed_response);
+        let cached_resources = self.entries.get_mut(&entry_key)?;
+        let mut refreshed_response = None;
+        // Only freshen the variants selected by the request which was revalidated,
+        // and whose validators match those of the 304 response.
+        // <https://tools.ietf.org/html/rfc7234#section-4.3.4>
+        // A 304 response naming an entity-tag selects the variant carrying it, which may
+        // not be the one selected by the request, as the server can pick any variant listed
+        // by the If-None-Match header of the conditional request.
+        // <https://tools.ietf.org/html/rfc7234#section-4.3.1>
+        // Resources older than the maximum entry age are treated as if they weren't stored.
+        let selected_by_entity_tag = response.headers.contains_key(header::ETAG);
+        let now = self.clock.now_instant();
+        let max_entry_age = self.max_entry_age;
+        let refreshable = |resource: &CachedResource| {
+            resource.method == request.method &&
+                !resource_exceeds_max_entry_age(resource, now, max_entry_age) &&
+                !resource.is_poisoned()
+        };
+        let mut selected: Vec<usize> = cached_resources
+            .iter()
+            .enumerate()
+            .filter(|(_, resource)| {
+                refreshable(resource) &&
+                    (selected_by_entity_tag || vary_matches(request, resource)) &&
+                    validators_match(resource, &response.headers)
+            })
+            .map(|(index, _)| index)
+            .collect();
+        if selected.is_empty() {
+            // Fetch always refreshes the stored response selected by the request when
+            // the validators of the 304 response don't select any, for example when only
+            // the 304 response carries an entity-tag. Like for the conditional request,
+            // the most recently validated one is picked among several.
+            // <https://fetch.spec.whatwg.org/#http-network-or-cache-fetch>
+            selected.extend(
+                cached_resources
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, resource)| {
+                        refreshable(resource) && vary_matches(request, resource)
+                    })
+                    .max_by_key(|(_, resource)| resource.last_validated)
+                    .map(|(index, _)| index),
+            );
+        }
+        let (stubs, selected): (Vec<usize>, Vec<usize>) = selected
+            .into_iter()
+            .partition(|index| cached_resources[*index].body_dropped);
+        for index in selected {
+            let cached_resource = &mut cached_resources[index];
+            // Received a response with 304 status code, in response to a request that matches a cached resource.
+            // 1. update the headers of the cached resource.
+            // 2. return a response, constructed from the cached resource.
+            let resource_timing = ResourceFetchTiming::new(request.timing_type());
+            let mut constructed_response =
+                Response::new(cached_resource.metadata.final_url.clone(), resource_timing);
+            constructed_response.body = served_body(cached_resource);
+            constructed_response
+                .status
+                .clone_from(&cached_resource.status);
+            constructed_response.https_state = cached_resource.https_state;
+            constructed_response.referrer = request.referrer.to_url().cloned();
+            constructed_response.referrer_policy = request.referrer_policy;
+            constructed_response
+                .url_list
+                .clone_from(&cached_resource.url_list);
+            let (updated_secondary_key, updated_headers) = {
+                let mut stored_headers = cached_resource.lock_headers();
+                stored_headers.extend(response.headers.clone());
+                strip_hop_by_hop_headers(&mut stored_headers);
+                strip_user_specific_headers(self.mode, &mut stored_headers);
+                // The updated headers may nominate different fields in their Vary header.
+                let updated_secondary_key =
+                    secondary_key(&stored_headers, &cached_resource.lock_request_headers());
+                (updated_secondary_key, stored_headers.clone())
+            };
+            cached_resource.secondary_key = updated_secondary_key;
+            constructed_response.headers = updated_headers;
+            constructed_response
+                .headers
+                .extend(lock_unpoisoned(&cached_resource.metadata.trailers).clone());
+            cached_resource.expires = get_response_expiry(
+                &constructed_response,
+                self.clock.now_system(),
+                self.default_ttl,
+                &self.extra_cacheable_status,
+                &self.heuristic_caps,
+                self.mode,
+            );
+            if let Some(cache_control_override) = self.cache_control_override {
+                cached_resource.expires = cache_control_override.apply(cached_resource.expires);
+            }
+            cached_resource.last_validated = self.clock.now_instant();
+            cached_resource
+                .revalidation_requested
+                .store(false, Ordering::Release);
+
+            if refreshed_response.is_some() {
+                continue;
+            }
+            // done_chan will have been set to Some(..) by http_network_fetch.
+            // If the body is not receiving data, set the done_chan back to None.
+            // Otherwise, create a new dedicated channel to update the consumer.
+            // The response constructed here will replace the 304 one from the network.
+            let mut awaiting_body = lock_unpoisoned(&cached_resource.awaiting_body);
+            let in_progress_channel = match *cached_resource.lock_body() {
+                ResponseBody::Receiving(..) => Some(unbounded()),
+                ResponseBody::Empty | ResponseBody::Done(..) => None,
+            };
+            match in_progress_channel {
+                Some((done_sender, done_receiver)) => {
+                    *done_chan = Some((done_sender.clone(), done_receiver));
+                    awaiting_body.push(done_sender);
+                },
+                None => *done_chan = None,
             }
+            refreshed_response = Some(constructed_response);
         }
-        None
+        for index in stubs.into_iter().rev() {
+            let stub = cached_resources.remove(index);
+            notify_eviction(&self.on_evict, &self.range_index, &entry_key, &stub);
+        }
+        if cached_resources.is_empty() {
+            self.entries.remove(&entry_key);
+        }
+        refreshed_response
+    }
+
+    /// Freshening Stored Responses upon Validation of a request which was itself
+    /// conditional, for example one forwarding the validators of a client.
+    /// When those validators still match the refreshed resource, a 304 with the updated
+    /// headers is returned so that it can be passed through, instead of the full response.
+    /// <https://tools.ietf.org/html/rfc7234#section-4.3.2>
+    pub fn refresh_conditional(
+        &mut self,
+        request: &Request,
+        response: Response,
+        done_chan: &mut DoneChannel,
+    ) -> Option<Response> {
+        let mut refreshed_response = self.refresh(request, response, done_chan)?;
+        if !request_validators_match(request, &refreshed_response.headers) {
+            return Some(refreshed_response);
+        }
+        *done_chan = None;
+        refreshed_response.status = StatusCode::NOT_MODIFIED.into();
+        refreshed_response.body = Arc::new(Mutex::new(ResponseBody::Empty));
+        Some(refreshed_response)
     }
 
     fn invalidate_for_url(&mut self, url: &ServoUrl) {
@@ -730,9 +2959,29 @@ impl HttpCache {
         }
     }
 
+    /// Invalidate all resources stored for a url, for example following an
+    /// out-of-band signal from the embedder. The resources are kept for conditional
+    /// revalidation, but must be validated before their next use.
+    pub fn invalidate_url(&mut self, url: &ServoUrl) {
+        self.invalidate_for_url(url);
+    }
+
+    /// Invalidate every stored resource, for example after a configuration change
+    /// affecting how responses are used. Bodies and validators are kept, so the
+    /// resources can still be revalidated with a conditional request.
+    pub fn expire_all(&mut self) {
+        for cached_resource in self.entries.values_mut().flatten() {
+            cached_resource.expires = Duration::ZERO;
+        }
+    }
+
     /// Invalidation.
     /// <https://tools.ietf.org/html/rfc7234#section-4.4>
     pub fn invalidate(&mut self, request: &Request, response: &Response) {
+        // Only a non-error response to an unsafe request invalidates stored responses.
+        if request.method.is_safe() || !response.status.in_range(200..=399) {
+            return;
+        }
         // TODO(eijebong): Once headers support typed_get, update this to use them
         if let Some(Ok(location)) = response
             .headers
@@ -755,27 +3004,111 @@ impl HttpCache {
         self.invalidate_for_url(&request.url());
     }
 
+    /// Purge the resources stored for the origin of a request when the response asks for it,
+    /// with the "cache" or "*" types of its `Clear-Site-Data` header. Only responses from
+    /// potentially trustworthy urls are honored.
+    /// <https://w3c.github.io/webappsec-clear-site-data/#header>
+    pub fn apply_clear_site_data(&mut self, request: &Request, response: &Response) {
+        let url = request.current_url();
+        if !url.is_potentially_trustworthy() {
+            return;
+        }
+        let clears_cache = response
+            .headers
+            .get_all("clear-site-data")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|data_type| matches!(data_type.trim(), "\"cache\"" | "\"*\""));
+        if !clears_cache {
+            return;
+        }
+        let origin = url.origin();
+        self.retain(|url, _| url.origin() != origin);
+    }
+
     /// Storing Responses in Caches.
     /// <https://tools.ietf.org/html/rfc7234#section-3>
     pub fn store(&mut self, request: &Request, response: &Response) {
+        self.store_many([(request, response)]);
+    }
+
+    /// Store a batch of responses, for example from a prefetcher, each of them subject to
+    /// the same rules as with `store`, evicting resources to fit the memory budget only
+    /// once the whole batch is stored.
+    pub fn store_many<'a>(&mut self, items: impl IntoIterator<Item = (&'a Request, &'a Response)>) {
+        for (request, response) in items {
+            self.store_resource(request, response);
+        }
+        self.evict_to_memory_budget();
+    }
+
+    /// Store a response if it is cacheable, without enforcing the memory budget.
+    fn store_resource(&mut self, request: &Request, response: &Response) {
         if pref!(network_http_cache_disabled) {
             return;
         }
+        if self
+            .store_filter
+            .as_ref()
+            .is_some_and(|store_filter| !store_filter(request, response))
+        {
+            return;
+        }
+        // A filtered response, such as a CORS one, only exposes some of the headers of
+        // the network response, which are needed to tell its freshness and the request
+        // headers it varies on, for example with `Vary: Origin`.
+        let filtered_response = response;
+        let response = response.actual_response();
+        if request.cache_mode == CacheMode::NoStore {
+            // The cache mode of the request forbids updating the cache.
+            // <https://fetch.spec.whatwg.org/#concept-request-cache-mode>
+            return;
+        }
+        if !self.cache_with_cookies &&
+            (request.headers.contains_key(header::COOKIE) ||
+                response.headers.contains_key(header::SET_COOKIE))
+        {
+            // Responses which may be specific to a user aren't stored when opted out,
+            // for example on shared devices.
+            return;
+        }
         if request.method != Method::GET {
             // Only Get requests are cached.
             return;
         }
-        if request.headers.contains_key(header::AUTHORIZATION) {
-            // https://tools.ietf.org/html/rfc7234#section-3.1
-            // A shared cache MUST NOT use a cached response
-            // to a request with an Authorization header field
-            //
-            // TODO: unless a cache directive that allows such
-            // responses to be stored is present in the response.
+        if request.headers.contains_key(header::AUTHORIZATION) &&
+            !authorized_response_is_storable(self.mode, &response.headers)
+        {
             return;
         };
+        if response
+            .headers
+            .typed_get::<Vary>()
+            .is_some_and(|vary| vary.is_any())
+        {
+            // A Vary header field-value of "*" always fails to match,
+            // so the response could never be constructed from the cache.
+            // <https://tools.ietf.org/html/rfc7234#section-4.1>
+            return;
+        }
+        if self.body_exceeds_max_size(response) {
+            return;
+        }
+        if !partial_body_matches_content_range(response) {
+            return;
+        }
         let entry_key = CacheKey::new(request);
-        let metadata = match response.metadata() {
+        let metadata = match filtered_response.metadata() {
+            // Opaque responses must not be exposed across origins,
+            // which constructing them from the cache could do.
+            Ok(FetchMetadata::Filtered {
+                filtered: FilteredMetadata::Opaque | FilteredMetadata::OpaqueRedirect(_),
+                ..
+            }) => {
+                debug!("opaque response, not caching");
+                return;
+            },
             Ok(FetchMetadata::Filtered {
                 filtered: _,
                 unsafe_: metadata,
@@ -783,39 +3116,396 @@ impl HttpCache {
             Ok(FetchMetadata::Unfiltered(metadata)) => metadata,
             _ => return,
         };
-        if !response_is_cacheable(&metadata) {
+        if !response_is_cacheable(self.mode, &metadata, &self.extra_cacheable_status) {
+            return;
+        }
+        self.insert_resource(
+            entry_key,
+            request.method.clone(),
+            &request.headers,
+            response,
+            metadata,
+        );
+        // TODO: Complete incomplete responses, including 206 response, when stored here.
+        // See A cache MAY complete a stored incomplete response by making a subsequent range request
+        // https://tools.ietf.org/html/rfc7234#section-3.1
+    }
+
+    /// Store the trailers of a response, received after its body, with the resource
+    /// most recently stored for the request, so that they are served along with its headers.
+    /// <https://tools.ietf.org/html/rfc7230#section-4.1.2>
+    pub fn store_trailers(&self, request: &Request, trailers: HeaderMap) {
+        let entry_key = CacheKey::new(request);
+        let Some(cached_resource) = self.entries.get(&entry_key).and_then(|resources| {
+            resources
+                .iter()
+                .rev()
+                .find(|resource| !resource.is_poisoned() && vary_matches(request, resource))
+        }) else {
             return;
+        };
+        let mut stored_trailers = trailers;
+        strip_hop_by_hop_headers(&mut stored_trailers);
+        *lock_unpoisoned(&cached_resource.metadata.trailers) = stored_trailers;
+    }
+
+    /// Store a cacheable response as a new resource.
+    fn insert_resource(
+        &mut self,
+        entry_key: CacheKey,
+        method: Method,
+        request_headers: &HeaderMap,
+        response: &Response,
+        metadata: Metadata,
+    ) {
+        let mut expiry = get_response_expiry(
+            response,
+            self.clock.now_system(),
+            self.default_ttl,
+            &self.extra_cacheable_status,
+            &self.heuristic_caps,
+            self.mode,
+        );
+        if let Some(cache_control_override) = self.cache_control_override {
+            expiry = cache_control_override.apply(expiry);
+        }
+        let mut stored_headers = response.headers.clone();
+        strip_hop_by_hop_headers(&mut stored_headers);
+        strip_user_specific_headers(self.mode, &mut stored_headers);
+        if stored_headers.typed_get::<Date>().is_none() {
+            // A cache receiving a response without a valid Date header must add one,
+            // set to the time it was received, so that its age has a stable reference.
+            // <https://tools.ietf.org/html/rfc7231#section-7.1.1.2>
+            stored_headers.typed_insert(Date::from(self.clock.now_system()));
         }
-        let expiry = get_response_expiry(response);
         let cacheable_metadata = CachedMetadata {
-            headers: Arc::new(Mutex::new(response.headers.clone())),
+            headers: Arc::new(Mutex::new(stored_headers)),
+            trailers: Arc::new(Mutex::new(HeaderMap::new())),
             final_url: metadata.final_url,
             content_type: metadata.content_type.map(|v| v.0.to_string()),
             charset: metadata.charset,
             status: metadata.status,
         };
-        let entry_resource = CachedResource {
-            request_headers: Arc::new(Mutex::new(request.headers.clone())),
+        let outcome = match *lock_unpoisoned(&response.body) {
+            ResponseBody::Receiving(_) => FetchOutcome::Pending,
+            ResponseBody::Done(_) | ResponseBody::Empty => FetchOutcome::Completed,
+        };
+        let mut entry_resource = CachedResource {
+            method,
+            request_headers: Arc::new(Mutex::new(request_headers.clone())),
+            secondary_key: secondary_key(&response.headers, request_headers),
             body: response.body.clone(),
+            body_compressed: false,
+            body_hash: None,
+            body_dropped: false,
             aborted: response.aborted.clone(),
             awaiting_body: Arc::new(Mutex::new(vec![])),
+            producer: Arc::new(Mutex::new(BodyProducer::Original)),
+            outcome: Arc::new(Mutex::new(outcome)),
+            revalidation_requested: Arc::new(AtomicBool::new(false)),
+            hit_count: Arc::new(AtomicU64::new(0)),
+            last_access: Arc::new(AtomicU64::new(next_access_tick())),
             metadata: cacheable_metadata,
             location_url: response.location_url.clone(),
             https_state: response.https_state,
             status: response.status.clone(),
             url_list: response.url_list.clone(),
             expires: expiry,
-            last_validated: Instant::now(),
+            last_validated: self.clock.now_instant(),
+            #[cfg(feature = "lock-contention")]
+            contention: Arc::new(LockContention::default()),
         };
-        let entry = self.entries.entry(entry_key).or_default();
+        complete_stored_body(&mut entry_resource, self.compress_bodies, self.hash_bodies);
+        if let Some(max_keys) = self.max_keys {
+            if !self.entries.contains_key(&entry_key) {
+                // Make room for the new key.
+                self.evict_keys_over(max_keys.saturating_sub(1));
+            }
+        }
+        let max_variants = self.max_variants_per_key;
+        let entry = self.entries.entry(entry_key.clone()).or_default();
+        // The resource fetched again replaces the stubs whose body was dropped,
+        // and the resources left inconsistent by a panic.
+        entry.retain(|resource| {
+            if !resource.body_dropped && !resource.is_poisoned() {
+                return true;
+            }
+            notify_eviction(&self.on_evict, &self.range_index, &entry_key, resource);
+            false
+        });
+        // Make room for the new variant by evicting the least recently validated ones.
+        while !entry.is_empty() && entry.len() >= max_variants {
+            let oldest = entry
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, resource)| resource.last_validated)
+                .map(|(index, _)| index)
+                .unwrap_or(0);
+            let evicted = entry.remove(oldest);
+            notify_eviction(&self.on_evict, &self.range_index, &entry_key, &evicted);
+        }
         entry.push(entry_resource);
-        // TODO: Complete incomplete responses, including 206 response, when stored here.
-        // See A cache MAY complete a stored incomplete response by making a subsequent range request
-        // https://tools.ietf.org/html/rfc7234#section-3.1
     }
 
-    /// Clear the contents of this cache.
+    /// Warm the cache with a resource built outside of a fetch, for example from
+    /// a snapshot of a previous session, or a prefetch. Statuses which aren't
+    /// cacheable by default are not stored, nor are responses which `store` would refuse,
+    /// for example those carrying a no-store directive.
+    pub fn insert_prebuilt(
+        &mut self,
+        url: ServoUrl,
+        headers: HeaderMap,
+        body: Vec<u8>,
+        status: StatusCode,
+    ) {
+        if !is_cacheable_by_default(status, &self.extra_cacheable_status) {
+            return;
+        }
+        let resource_timing = ResourceFetchTiming::new(ResourceTimingType::None);
+        let mut response = Response::new(url.clone(), resource_timing);
+        response.headers = headers;
+        response.status = status.into();
+        *lock_unpoisoned(&response.body) = ResponseBody::Done(body);
+        if self.body_exceeds_max_size(&response) {
+            return;
+        }
+        let Ok(FetchMetadata::Unfiltered(metadata)) = response.metadata() else {
+            return;
+        };
+        if !response_is_cacheable(self.mode, &metadata, &self.extra_cacheable_status) {
+            return;
+        }
+        let entry_key = CacheKey::from_servo_url(&url);
+        self.insert_resource(
+            entry_key,
+            Method::GET,
+            &HeaderMap::new(),
+            &response,
+            metadata,
+        );
+        self.evict_to_memory_budget();
+    }
+
+    /// Iterate over information about every cached resource.
+    pub fn iter_entries(&self) -> impl Iterator<Item = CacheEntryInfo> {
+        self.entries
+            .iter()
+            .flat_map(|(entry_key, cached_resources)| {
+                cached_resources
+                    .iter()
+                    .filter(|cached_resource| !cached_resource.is_poisoned())
+                    .map(move |cached_resource| CacheEntryInfo::new(entry_key, cached_resource))
+            })
+    }
+
+    /// Export every cached resource as a HAR entry, leaving their bodies out.
+    pub fn to_har_entries(&self) -> Vec<HarEntry> {
+        self.har_entries(false)
+    }
+
+    /// Export every cached resource as a HAR entry, including their fully received bodies.
+    pub fn to_har_entries_with_bodies(&self) -> Vec<HarEntry> {
+        self.har_entries(true)
+    }
+
+    fn har_entries(&self, include_bodies: bool) -> Vec<HarEntry> {
+        self.entries
+            .iter()
+            .flat_map(|(entry_key, cached_resources)| {
+                cached_resources
+                    .iter()
+                    .filter(|cached_resource| !cached_resource.is_poisoned())
+                    .map(move |cached_resource| {
+                        HarEntry::new(entry_key, cached_resource, include_bodies)
+                    })
+            })
+            .collect()
+    }
+
+    /// Information about the freshest resource stored for a url, for inspection purposes only.
+    /// As there is no request to match them against, `Vary` headers are ignored.
+    pub fn get_by_url(&self, url: &ServoUrl) -> Option<CacheEntryInfo> {
+        let entry_key = CacheKey::from_servo_url(url);
+        let now = self.clock.now_instant();
+        self.entries
+            .get(&entry_key)?
+            .iter()
+            .filter(|r| !r.aborted.load(Ordering::Relaxed))
+            .filter(|r| !resource_exceeds_max_entry_age(r, now, self.max_entry_age))
+            .filter(|r| !r.is_poisoned())
+            .filter(|r| r.method == Method::GET)
+            .max_by_key(|r| {
+                let freshness_remaining = r.expires.saturating_sub(now - r.last_validated);
+                (freshness_remaining, r.last_validated)
+            })
+            .map(|cached_resource| CacheEntryInfo::new(&entry_key, cached_resource))
+    }
+
+    /// All the resources stored for a url, in the order they were stored, with the request
+    /// header values selecting them, for debugging `Vary` related misses.
+    pub fn variants_for(&self, url: &ServoUrl) -> Vec<VariantInfo> {
+        let entry_key = CacheKey::from_servo_url(url);
+        self.entries
+            .get(&entry_key)
+            .map(|cached_resources| {
+                cached_resources
+                    .iter()
+                    .filter(|cached_resource| !cached_resource.is_poisoned())
+                    .map(VariantInfo::new)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// The fully received body of the freshest complete resource stored for a url,
+    /// for tooling and tests to verify what is cached. Partial responses are ignored,
+    /// and a body stored compressed is returned decompressed.
+    pub fn body_bytes_for(&self, url: &ServoUrl) -> Option<Vec<u8>> {
+        let entry_key = CacheKey::from_servo_url(url);
+        let now = self.clock.now_instant();
+        let cached_resource = self
+            .entries
+            .get(&entry_key)?
+            .iter()
+            .filter(|r| !r.aborted.load(Ordering::Relaxed))
+            .filter(|r| !resource_exceeds_max_entry_age(r, now, self.max_entry_age))
+            .filter(|r| !r.is_poisoned())
+            .filter(|r| r.method == Method::GET && r.status == StatusCode::OK)
+            .filter(|r| matches!(*r.lock_body(), ResponseBody::Done(_)))
+            .max_by_key(|r| {
+                let freshness_remaining = r.expires.saturating_sub(now - r.last_validated);
+                (freshness_remaining, r.last_validated)
+            })?;
+        match *lock_unpoisoned(&served_body(cached_resource)) {
+            ResponseBody::Done(ref bytes) => Some(bytes.clone()),
+            ResponseBody::Receiving(_) | ResponseBody::Empty => None,
+        }
+    }
+
+    /// Drop the fully received bodies of all resources to reclaim memory, for example under
+    /// memory pressure, keeping their metadata. The resulting stubs are never used to construct
+    /// a response, but still provide validators, so that a conditional request can fetch
+    /// their body again.
+    pub fn drop_bodies_keeping_metadata(&mut self) {
+        for cached_resource in self.entries.values_mut().flatten() {
+            if !matches!(*cached_resource.lock_body(), ResponseBody::Done(_)) {
+                continue;
+            }
+            // The body may be shared with responses being served, which keep their own.
+            cached_resource.body = Arc::new(Mutex::new(ResponseBody::Empty));
+            cached_resource.body_compressed = false;
+            cached_resource.body_hash = None;
+            cached_resource.body_dropped = true;
+        }
+        lock_unpoisoned(&self.range_index).clear();
+    }
+
+    /// The urls of the resources whose body no longer matches the hash computed when it was
+    /// stored, which would reveal a memory corruption, or a bug. Only bodies hashed while
+    /// `set_hash_bodies` was enabled are verified.
+    pub fn verify_integrity(&self) -> Vec<ServoUrl> {
+        self.entries
+            .iter()
+            .flat_map(|(entry_key, cached_resources)| {
+                cached_resources
+                    .iter()
+                    .map(move |cached_resource| (entry_key, cached_resource))
+            })
+            .filter(|(_, cached_resource)| {
+                cached_resource.body_hash.is_some_and(|body_hash| {
+                    match *cached_resource.lock_body() {
+                        ResponseBody::Done(ref bytes) => hash_body(bytes) != body_hash,
+                        ResponseBody::Receiving(_) | ResponseBody::Empty => true,
+                    }
+                })
+            })
+            .map(|(entry_key, _)| entry_key.url.clone())
+            .collect()
+    }
+
+    /// The total size of the fully received bodies stored in the cache,
+    /// excluding the overhead of their metadata and headers.
+    pub fn body_bytes(&self) -> usize {
+        self.entries
+            .values()
+            .flatten()
+            .map(|cached_resource| match *cached_resource.lock_body() {
+                ResponseBody::Done(ref body) => body.len(),
+                ResponseBody::Receiving(_) | ResponseBody::Empty => 0,
+            })
+            .sum()
+    }
+
+    /// Only keep the cached resources for which `f` returns true,
+    /// dropping now empty entries. The eviction callback is notified of the others.
+    pub fn retain<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&ServoUrl, &CachedMetadataView) -> bool,
+    {
+        let on_evict = &self.on_evict;
+        let range_index = &self.range_index;
+        self.entries.retain(|entry_key, cached_resources| {
+            cached_resources.retain(|cached_resource| {
+                let metadata = &cached_resource.metadata;
+                let view = CachedMetadataView {
+                    final_url: &metadata.final_url,
+                    content_type: metadata.content_type.as_deref(),
+                    charset: metadata.charset.as_deref(),
+                    status: &metadata.status,
+                    last_validated: cached_resource.last_validated,
+                };
+                if f(&entry_key.url, &view) {
+                    return true;
+                }
+                if let ResponseBody::Receiving(_) = *cached_resource.lock_body() {
+                    // The body still being received no longer completes a stored resource.
+                    cached_resource.aborted.store(true, Ordering::Release);
+                }
+                // Consumers waiting on the body would otherwise never be woken-up,
+                // have them start a new network request instead.
+                notify_eviction(on_evict, range_index, entry_key, cached_resource);
+                false
+            });
+            !cached_resources.is_empty()
+        });
+    }
+
+    /// The urls whose stored resources had their locks contended the most, by the total time
+    /// spent waiting for them, for example to decide whether to move to finer-grained locks.
+    #[cfg(feature = "lock-contention")]
+    pub fn contention_report(&self) -> Vec<ContentionReportEntry> {
+        let mut report: Vec<ContentionReportEntry> = self
+            .entries
+            .iter()
+            .map(|(entry_key, cached_resources)| {
+                let (contended, waited_nanos) = cached_resources.iter().fold(
+                    (0, 0),
+                    |(contended, waited_nanos): (u64, u64), resource| {
+                        (
+                            contended + resource.contention.contended.load(Ordering::Relaxed),
+                            waited_nanos + resource.contention.waited_nanos.load(Ordering::Relaxed),
+                        )
+                    },
+                );
+                ContentionReportEntry {
+                    url: entry_key.url.clone(),
+                    contended,
+                    waited: Duration::from_nanos(waited_nanos),
+                }
+            })
+            .filter(|entry| entry.contended > 0)
+            .collect();
+        report.sort_by_key(|entry| std::cmp::Reverse(entry.waited));
+        report
+    }
+
+    /// Clear the contents of this cache. The consumers waiting on a body are woken-up
+    /// to make their own network requests.
     pub fn clear(&mut self) {
+        for cached_resource in self.entries.values().flatten() {
+            cancel_awaiting_consumers(cached_resource);
+        }
         self.entries.clear();
+        lock_unpoisoned(&self.range_index).clear();
     }
 }
@@ -1551,6 +1551,14 @@ async fn http_network_or_cache_fetch(
             }
         }
 
+        // Purge the stored responses of the origin if the response asks for it.
+        // <https://w3c.github.io/webappsec-clear-site-data/#header>
+        if forward_response.headers.contains_key("clear-site-data") {
+            if let Ok(mut http_cache) = context.state.http_cache.write() {
+                http_cache.apply_clear_site_data(http_request, &forward_response);
+            }
+        }
+
         // Step 10.4 If the revalidatingFlag is set and forwardResponse’s status is 304, then:
         if revalidating_flag && forward_response.status == StatusCode::NOT_MODIFIED {
             if let Ok(mut http_cache) = context.state.http_cache.write() {
@@ -2,15 +2,35 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 
+use std::io::Write;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex, RwLock, mpsc};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
 use base::id::TEST_PIPELINE_ID;
-use http::StatusCode;
-use http::header::{EXPIRES, HeaderValue};
-use net::http_cache::HttpCache;
-use net_traits::request::{Referrer, RequestBuilder};
-use net_traits::response::{Response, ResponseBody};
-use net_traits::{ResourceFetchTiming, ResourceTimingType};
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use headers::{Date, Expires, HeaderMapExt};
+use http::header::{
+    ACCEPT_ENCODING, ACCEPT_LANGUAGE, ACCEPT_RANGES, AGE, AUTHORIZATION, CACHE_CONTROL, CONNECTION,
+    CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, COOKIE, DATE, ETAG, EXPIRES,
+    HeaderName, HeaderValue, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, ORIGIN, PRAGMA,
+    RANGE, SET_COOKIE, TRAILER, TRANSFER_ENCODING, VARY, VIA,
+};
+use http::{HeaderMap, Method, StatusCode};
+use malloc_size_of::{MallocSizeOf, MallocSizeOfOps};
+use net::fetch::methods::Data;
+use net::http_cache::{
+    CacheControlOverride, CacheLookupExplanation, CachedResponse, EvictionPolicy, HttpCache,
+    HttpCacheMode, MockClock, VariantInfo,
+};
+use net_traits::request::{CacheMode, Referrer, Request, RequestBuilder};
+use net_traits::response::{HttpsState, Response, ResponseBody, ResponseType};
+use net_traits::{NetworkError, ResourceFetchTiming, ResourceTimingType};
+use servo_arc::Arc as ServoArc;
 use servo_url::ServoUrl;
-use tokio::sync::mpsc::unbounded_channel as unbounded;
+use tokio::sync::mpsc::{UnboundedReceiver as Receiver, unbounded_channel as unbounded};
 
 #[test]
 fn test_refreshing_resource_sets_done_chan_the_appropriate_value() {
@@ -48,3 +68,3896 @@ fn test_refreshing_resource_sets_done_chan_the_appropriate_value() {
         }
     })
 }
+
+fn create_request(url: &ServoUrl, headers: HeaderMap) -> Request {
+    RequestBuilder::new(None, url.clone(), Referrer::NoReferrer)
+        .pipeline_id(Some(TEST_PIPELINE_ID))
+        .origin(url.origin())
+        .headers(headers)
+        .build()
+}
+
+fn create_response(url: &ServoUrl, headers: HeaderMap, body: ResponseBody) -> Response {
+    let timing = ResourceFetchTiming::new(ResourceTimingType::Navigation);
+    let mut response = Response::new(url.clone(), timing);
+    response.headers = headers;
+    *response.body.lock().unwrap() = body;
+    response
+}
+
+fn header_map(fields: &[(HeaderName, &'static str)]) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    for (name, value) in fields {
+        headers.insert(name.clone(), HeaderValue::from_static(value));
+    }
+    headers
+}
+
+/// Store a response with the given status and headers in a cache set up by `configure`,
+/// then look it up `elapsed` seconds later with a request carrying the given headers,
+/// returning whether the resource needs validation.
+fn store_and_lookup(
+    status: StatusCode,
+    response_headers: HeaderMap,
+    request_headers: HeaderMap,
+    elapsed: u64,
+    configure: impl FnOnce(&mut HttpCache),
+) -> bool {
+    let url = ServoUrl::parse("https://servo.org/freshness").unwrap();
+    let request = create_request(&url, HeaderMap::new());
+    let mut response = create_response(&url, response_headers, ResponseBody::Done(vec![]));
+    response.status = status.into();
+    let clock = MockClock::default();
+    let mut cache = HttpCache::default();
+    cache.set_clock(Box::new(clock.clone()));
+    configure(&mut cache);
+    cache.store(&request, &response);
+
+    clock.advance(Duration::from_secs(elapsed));
+    let request = create_request(&url, request_headers);
+    cache
+        .construct_response(&request, &mut None)
+        .unwrap()
+        .needs_validation
+}
+
+/// Store a response carrying the given headers, for a request carrying the given headers,
+/// once `configure` has set up the cache and that request, then construct a response
+/// for a request carrying the same headers.
+fn store_and_construct(
+    request_headers: HeaderMap,
+    response_headers: HeaderMap,
+    configure: impl FnOnce(&mut HttpCache, &mut Request),
+) -> Option<CachedResponse> {
+    let url = ServoUrl::parse("https://servo.org/stored").unwrap();
+    let mut request = create_request(&url, request_headers.clone());
+    let response = create_response(&url, response_headers, ResponseBody::Done(b"body".to_vec()));
+    let mut cache = HttpCache::default();
+    configure(&mut cache, &mut request);
+    cache.store(&request, &response);
+    cache.construct_response(&create_request(&url, request_headers), &mut None)
+}
+
+#[test]
+fn test_conditional_headers_reflect_stored_validators() {
+    let url = ServoUrl::parse("https://servo.org/validators").unwrap();
+    let request = create_request(&url, HeaderMap::new());
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=0"));
+    headers.insert(ETAG, HeaderValue::from_static("\"abc\""));
+    headers.insert(
+        LAST_MODIFIED,
+        HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"),
+    );
+    let response = create_response(&url, headers, ResponseBody::Done(b"body".to_vec()));
+    let mut cache = HttpCache::default();
+    cache.store(&request, &response);
+
+    let conditional_headers = cache
+        .conditional_headers_for(&request)
+        .expect("a stale resource with validators should be revalidated");
+    assert_eq!(conditional_headers.get(IF_NONE_MATCH).unwrap(), "\"abc\"");
+    assert_eq!(
+        conditional_headers.get(IF_MODIFIED_SINCE).unwrap(),
+        "Wed, 21 Oct 2015 07:28:00 GMT"
+    );
+}
+
+#[test]
+fn test_conditional_headers_without_validators() {
+    let url = ServoUrl::parse("https://servo.org/no-validators").unwrap();
+    let request = create_request(&url, HeaderMap::new());
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=0"));
+    let response = create_response(&url, headers, ResponseBody::Done(b"body".to_vec()));
+    let mut cache = HttpCache::default();
+    cache.store(&request, &response);
+
+    assert!(cache.conditional_headers_for(&request).is_none());
+}
+
+#[test]
+fn test_refresh_only_freshens_matching_variant() {
+    let url = ServoUrl::parse("https://servo.org/variants").unwrap();
+    let mut cache = HttpCache::default();
+    let mut requests = vec![];
+    for encoding in ["gzip", "br"] {
+        let mut request_headers = HeaderMap::new();
+        request_headers.insert(ACCEPT_ENCODING, HeaderValue::from_static(encoding));
+        let request = create_request(&url, request_headers);
+        let mut headers = HeaderMap::new();
+        headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=0"));
+        headers.insert(VARY, HeaderValue::from_static("accept-encoding"));
+        let response = create_response(&url, headers, ResponseBody::Done(encoding.into()));
+        cache.store(&request, &response);
+        requests.push(request);
+    }
+    let (gzip_request, br_request) = (&requests[0], &requests[1]);
+
+    let mut not_modified_headers = HeaderMap::new();
+    not_modified_headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+    not_modified_headers.insert(
+        HeaderName::from_static("x-refreshed"),
+        HeaderValue::from_static("1"),
+    );
+    let mut not_modified = create_response(&url, not_modified_headers, ResponseBody::Empty);
+    not_modified.status = StatusCode::NOT_MODIFIED.into();
+    let refreshed = cache
+        .refresh(gzip_request, not_modified, &mut None)
+        .expect("the gzip variant should be refreshed");
+    assert_eq!(
+        *refreshed.body.lock().unwrap(),
+        ResponseBody::Done(b"gzip".to_vec())
+    );
+
+    let gzip_response = cache.construct_response(gzip_request, &mut None).unwrap();
+    assert!(!gzip_response.needs_validation);
+    assert!(gzip_response.response.headers.contains_key("x-refreshed"));
+
+    let br_response = cache.construct_response(br_request, &mut None).unwrap();
+    assert!(br_response.needs_validation);
+    assert!(!br_response.response.headers.contains_key("x-refreshed"));
+}
+
+fn store_with_content_type(cache: &mut HttpCache, url: &ServoUrl, content_type: &'static str) {
+    let request = create_request(url, HeaderMap::new());
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+    let response = create_response(url, headers, ResponseBody::Done(b"body".to_vec()));
+    cache.store(&request, &response);
+}
+
+fn is_cached(cache: &HttpCache, url: &ServoUrl) -> bool {
+    let request = create_request(url, HeaderMap::new());
+    cache.construct_response(&request, &mut None).is_some()
+}
+
+#[test]
+fn test_retain_by_content_type() {
+    let html_url = ServoUrl::parse("https://servo.org/index.html").unwrap();
+    let css_url = ServoUrl::parse("https://servo.org/style.css").unwrap();
+    let mut cache = HttpCache::default();
+    store_with_content_type(&mut cache, &html_url, "text/html");
+    store_with_content_type(&mut cache, &css_url, "text/css");
+
+    cache.retain(|_, metadata| metadata.content_type != Some("text/html"));
+
+    assert!(!is_cached(&cache, &html_url));
+    assert!(is_cached(&cache, &css_url));
+}
+
+#[test]
+fn test_retain_by_url() {
+    let tracker_url = ServoUrl::parse("https://servo.org/tracker.js").unwrap();
+    let script_url = ServoUrl::parse("https://servo.org/script.js").unwrap();
+    let mut cache = HttpCache::default();
+    store_with_content_type(&mut cache, &tracker_url, "text/javascript");
+    store_with_content_type(&mut cache, &script_url, "text/javascript");
+
+    cache.retain(|url, _| !url.as_str().contains("tracker"));
+
+    assert!(!is_cached(&cache, &tracker_url));
+    assert!(is_cached(&cache, &script_url));
+}
+
+#[test]
+fn test_iter_entries_reports_sizes_and_content_types() {
+    let mut cache = HttpCache::default();
+    let resources = [
+        ("https://servo.org/a.html", "text/html", 10),
+        ("https://servo.org/b.css", "text/css", 20),
+        ("https://servo.org/c.png", "image/png", 30),
+    ];
+    for (url, content_type, size) in resources {
+        let url = ServoUrl::parse(url).unwrap();
+        let request = create_request(&url, HeaderMap::new());
+        let mut headers = HeaderMap::new();
+        headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+        let response = create_response(&url, headers, ResponseBody::Done(vec![0; size]));
+        cache.store(&request, &response);
+    }
+
+    let mut entries: Vec<_> = cache.iter_entries().collect();
+    entries.sort_by_key(|entry| entry.body_size);
+    assert_eq!(entries.len(), resources.len());
+    for (entry, (url, content_type, size)) in entries.iter().zip(resources) {
+        assert_eq!(entry.url.as_str(), url);
+        assert_eq!(entry.content_type.as_deref(), Some(content_type));
+        assert_eq!(entry.body_size, size);
+        assert!(!entry.is_receiving);
+    }
+}
+
+#[test]
+fn test_qualified_no_cache_strips_named_fields() {
+    let url = ServoUrl::parse("https://servo.org/qualified-no-cache").unwrap();
+    let request = create_request(&url, HeaderMap::new());
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        CACHE_CONTROL,
+        HeaderValue::from_static("max-age=3600, no-cache=\"Set-Cookie\""),
+    );
+    headers.insert(SET_COOKIE, HeaderValue::from_static("session=1"));
+    headers.insert(ETAG, HeaderValue::from_static("\"abc\""));
+    let response = create_response(&url, headers, ResponseBody::Done(b"body".to_vec()));
+    let mut cache = HttpCache::default();
+    cache.store(&request, &response);
+
+    let cached = cache.construct_response(&request, &mut None).unwrap();
+    assert!(!cached.needs_validation);
+    assert!(!cached.response.headers.contains_key(SET_COOKIE));
+    assert!(cached.response.headers.contains_key(ETAG));
+    assert_eq!(
+        *cached.response.body.lock().unwrap(),
+        ResponseBody::Done(b"body".to_vec())
+    );
+}
+
+#[test]
+fn test_unqualified_no_cache_forces_validation() {
+    let url = ServoUrl::parse("https://servo.org/no-cache").unwrap();
+    let request = create_request(&url, HeaderMap::new());
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        CACHE_CONTROL,
+        HeaderValue::from_static("max-age=3600, no-cache"),
+    );
+    headers.insert(SET_COOKIE, HeaderValue::from_static("session=1"));
+    let response = create_response(&url, headers, ResponseBody::Done(b"body".to_vec()));
+    let mut cache = HttpCache::default();
+    cache.store(&request, &response);
+
+    let cached = cache.construct_response(&request, &mut None).unwrap();
+    assert!(cached.needs_validation);
+}
+
+#[test]
+fn test_must_understand_with_understood_status_is_cacheable() {
+    let url = ServoUrl::parse("https://servo.org/must-understand").unwrap();
+    let request = create_request(&url, HeaderMap::new());
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        CACHE_CONTROL,
+        HeaderValue::from_static("must-understand, no-store, max-age=3600"),
+    );
+    let response = create_response(&url, headers, ResponseBody::Done(b"body".to_vec()));
+    let mut cache = HttpCache::default();
+    cache.store(&request, &response);
+
+    assert!(is_cached(&cache, &url));
+}
+
+#[test]
+fn test_must_understand_with_unknown_status_is_not_cacheable() {
+    let url = ServoUrl::parse("https://servo.org/teapot").unwrap();
+    let request = create_request(&url, HeaderMap::new());
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        CACHE_CONTROL,
+        HeaderValue::from_static("must-understand, no-store, max-age=3600"),
+    );
+    let mut response = create_response(&url, headers, ResponseBody::Done(b"body".to_vec()));
+    response.status = StatusCode::IM_A_TEAPOT.into();
+    let mut cache = HttpCache::default();
+    cache.store(&request, &response);
+
+    assert!(cache.iter_entries().next().is_none());
+}
+
+#[test]
+fn test_aborted_fetch_promotes_a_single_consumer() {
+    let url = ServoUrl::parse("https://servo.org/aborted").unwrap();
+    let request = create_request(&url, HeaderMap::new());
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+    let response = create_response(&url, headers, ResponseBody::Receiving(vec![]));
+    let mut cache = HttpCache::default();
+    cache.store(&request, &response);
+
+    let mut receivers = vec![];
+    for _ in 0..3 {
+        let (send, recv) = unbounded();
+        let mut done_chan = Some((send, recv));
+        assert!(cache.construct_response(&request, &mut done_chan).is_some());
+        receivers.push(done_chan.take().unwrap().1);
+    }
+
+    response.aborted.store(true, Ordering::Release);
+    cache.update_awaiting_consumers(&request, &response);
+
+    let restarts = receivers
+        .iter_mut()
+        .map(|recv| recv.try_recv())
+        .filter(|data| matches!(data, Ok(Data::Cancelled)))
+        .count();
+    assert_eq!(restarts, 1);
+
+    // The promoted consumer completes its network fetch, waking up the parked ones.
+    let completed_response =
+        create_response(&url, HeaderMap::new(), ResponseBody::Done(b"body".to_vec()));
+    cache.update_awaiting_consumers(&request, &completed_response);
+
+    let completions = receivers
+        .iter_mut()
+        .map(|recv| recv.try_recv())
+        .filter(|data| matches!(data, Ok(Data::Done)))
+        .count();
+    assert_eq!(completions, 2);
+}
+
+fn promoted_consumers_after(completed_response: &Response) -> (usize, usize) {
+    let url = ServoUrl::parse("https://servo.org/aborted").unwrap();
+    let request = create_request(&url, HeaderMap::new());
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+    let response = create_response(&url, headers, ResponseBody::Receiving(vec![]));
+    let mut cache = HttpCache::default();
+    cache.store(&request, &response);
+
+    let mut receivers = vec![];
+    for _ in 0..3 {
+        let (send, recv) = unbounded();
+        let mut done_chan = Some((send, recv));
+        assert!(cache.construct_response(&request, &mut done_chan).is_some());
+        receivers.push(done_chan.take().unwrap().1);
+    }
+    response.aborted.store(true, Ordering::Release);
+    cache.update_awaiting_consumers(&request, &response);
+    drop(receivers.remove(0));
+
+    cache.update_awaiting_consumers(&request, completed_response);
+    let received: Vec<_> = receivers.iter_mut().map(|recv| recv.try_recv()).collect();
+    let cancelled = received
+        .iter()
+        .filter(|data| matches!(data, Ok(Data::Cancelled)))
+        .count();
+    let done = received
+        .iter()
+        .filter(|data| matches!(data, Ok(Data::Done)))
+        .count();
+    (cancelled, done)
+}
+
+#[test]
+fn test_unusable_promoted_fetch_cancels_the_parked_consumers() {
+    let url = ServoUrl::parse("https://servo.org/aborted").unwrap();
+    let mut not_found = create_response(
+        &url,
+        HeaderMap::new(),
+        ResponseBody::Done(b"missing".to_vec()),
+    );
+    not_found.status = StatusCode::NOT_FOUND.into();
+    assert_eq!(promoted_consumers_after(&not_found), (2, 0));
+
+    let empty = create_response(&url, HeaderMap::new(), ResponseBody::Empty);
+    assert_eq!(promoted_consumers_after(&empty), (2, 0));
+}
+
+#[test]
+fn test_failed_promoted_fetch_promotes_the_next_consumer() {
+    let failed = Response::network_error(NetworkError::Internal("failed".into()));
+    assert_eq!(promoted_consumers_after(&failed), (1, 0));
+}
+
+#[test]
+fn test_consumer_which_stopped_waiting_is_not_promoted() {
+    let url = ServoUrl::parse("https://servo.org/aborted").unwrap();
+    let request = create_request(&url, HeaderMap::new());
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+    let response = create_response(&url, headers, ResponseBody::Receiving(vec![]));
+    let mut cache = HttpCache::default();
+    cache.store(&request, &response);
+
+    let mut receivers = vec![];
+    for _ in 0..3 {
+        let (send, recv) = unbounded();
+        let mut done_chan = Some((send, recv));
+        assert!(cache.construct_response(&request, &mut done_chan).is_some());
+        receivers.push(done_chan.take().unwrap().1);
+    }
+    drop(receivers.remove(0));
+
+    response.aborted.store(true, Ordering::Release);
+    cache.update_awaiting_consumers(&request, &response);
+
+    assert!(matches!(receivers[0].try_recv(), Ok(Data::Cancelled)));
+    assert!(receivers[1].try_recv().is_err());
+}
+
+fn store_gzipped_response(
+    cache: &mut HttpCache,
+    url: &ServoUrl,
+    cache_control: &'static str,
+    vary: Option<&'static str>,
+) {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(b"decoded body").unwrap();
+    let compressed = encoder.finish().unwrap();
+
+    // Unlike "gzip", the "x-gzip" alias isn't decoded by the fetch layer,
+    // so the body is stored encoded.
+    let mut request_headers = HeaderMap::new();
+    request_headers.insert(ACCEPT_ENCODING, HeaderValue::from_static("x-gzip"));
+    let request = create_request(url, request_headers);
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static(cache_control));
+    headers.insert(CONTENT_ENCODING, HeaderValue::from_static("x-gzip"));
+    if let Some(vary) = vary {
+        headers.insert(VARY, HeaderValue::from_static(vary));
+    }
+    let response = create_response(url, headers, ResponseBody::Done(compressed));
+    cache.store(&request, &response);
+}
+
+#[test]
+fn test_gzipped_resource_is_decoded_for_identity_request() {
+    let url = ServoUrl::parse("https://servo.org/gzipped").unwrap();
+    let mut cache = HttpCache::default();
+    store_gzipped_response(&mut cache, &url, "max-age=3600", None);
+
+    let mut headers = HeaderMap::new();
+    headers.insert(ACCEPT_ENCODING, HeaderValue::from_static("identity"));
+    let request = create_request(&url, headers);
+    let cached_response = cache.construct_response(&request, &mut None).unwrap();
+
+    assert!(
+        cached_response
+            .response
+            .headers
+            .get(CONTENT_ENCODING)
+            .is_none()
+    );
+    assert_eq!(
+        *cached_response.response.body.lock().unwrap(),
+        ResponseBody::Done(b"decoded body".to_vec())
+    );
+}
+
+#[test]
+fn test_gzipped_variant_with_vary_accept_encoding_is_not_decoded() {
+    let url = ServoUrl::parse("https://servo.org/gzipped-variant").unwrap();
+    let mut cache = HttpCache::default();
+    store_gzipped_response(&mut cache, &url, "max-age=3600", Some("accept-encoding"));
+
+    let mut headers = HeaderMap::new();
+    headers.insert(ACCEPT_ENCODING, HeaderValue::from_static("x-gzip"));
+    let request = create_request(&url, headers);
+    let cached_response = cache.construct_response(&request, &mut None).unwrap();
+
+    assert_eq!(
+        cached_response.response.headers.get(CONTENT_ENCODING),
+        Some(&HeaderValue::from_static("x-gzip"))
+    );
+    assert_ne!(
+        *cached_response.response.body.lock().unwrap(),
+        ResponseBody::Done(b"decoded body".to_vec())
+    );
+
+    // A request which doesn't accept x-gzip selects a separate variant, which isn't stored.
+    let mut headers = HeaderMap::new();
+    headers.insert(ACCEPT_ENCODING, HeaderValue::from_static("identity"));
+    let request = create_request(&url, headers);
+    assert!(cache.construct_response(&request, &mut None).is_none());
+}
+
+#[test]
+fn test_body_decoded_by_the_fetch_layer_is_not_decoded_again() {
+    // A gzip file served with a gzip content-coding reaches the cache with
+    // the content-coding removed by the fetch layer, but its header kept.
+    let url = ServoUrl::parse("https://servo.org/archive.gz").unwrap();
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(b"archived").unwrap();
+    let archive = encoder.finish().unwrap();
+    let request = create_request(&url, HeaderMap::new());
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+    headers.insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+    let response = create_response(&url, headers, ResponseBody::Done(archive.clone()));
+    let mut cache = HttpCache::default();
+    cache.store(&request, &response);
+
+    let mut headers = HeaderMap::new();
+    headers.insert(ACCEPT_ENCODING, HeaderValue::from_static("identity"));
+    let request = create_request(&url, headers);
+    let cached_response = cache.construct_response(&request, &mut None).unwrap();
+    assert_eq!(
+        *cached_response.response.body.lock().unwrap(),
+        ResponseBody::Done(archive)
+    );
+}
+
+#[test]
+fn test_invalidate_url_forces_validation() {
+    let url = ServoUrl::parse("https://servo.org/invalidated").unwrap();
+    let request = create_request(&url, HeaderMap::new());
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+    let response = create_response(&url, headers, ResponseBody::Done(b"body".to_vec()));
+    let mut cache = HttpCache::default();
+    cache.store(&request, &response);
+
+    let cached_response = cache.construct_response(&request, &mut None).unwrap();
+    assert!(!cached_response.needs_validation);
+
+    cache.invalidate_url(&url);
+    let cached_response = cache.construct_response(&request, &mut None).unwrap();
+    assert!(cached_response.needs_validation);
+}
+
+#[test]
+fn test_hit_count_tracks_served_responses() {
+    let url = ServoUrl::parse("https://servo.org/hits").unwrap();
+    let request = create_request(&url, HeaderMap::new());
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+    let response = create_response(&url, headers, ResponseBody::Done(b"body".to_vec()));
+    let mut cache = HttpCache::default();
+    cache.store(&request, &response);
+
+    for _ in 0..3 {
+        assert!(cache.construct_response(&request, &mut None).is_some());
+    }
+    let mut range_headers = HeaderMap::new();
+    range_headers.insert(RANGE, HeaderValue::from_static("bytes=0-1"));
+    let range_request = create_request(&url, range_headers);
+    assert!(
+        cache
+            .construct_response(&range_request, &mut None)
+            .is_some()
+    );
+
+    let entry = cache.iter_entries().next().unwrap();
+    assert_eq!(entry.hit_count, 4);
+}
+
+fn store_body(cache: &mut HttpCache, url: &ServoUrl, body: Vec<u8>) {
+    let request = create_request(url, HeaderMap::new());
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+    let response = create_response(url, headers, ResponseBody::Done(body));
+    cache.store(&request, &response);
+}
+
+fn evict_under_policy(eviction_policy: EvictionPolicy) -> (bool, bool) {
+    let hot_url = ServoUrl::parse("https://servo.org/hot.css").unwrap();
+    let large_url = ServoUrl::parse("https://servo.org/large.bin").unwrap();
+    let mut cache = HttpCache::default();
+    cache.set_eviction_policy(eviction_policy);
+    cache.set_memory_budget(Some(1024));
+
+    store_body(&mut cache, &hot_url, vec![0; 16]);
+    for _ in 0..3 {
+        assert!(is_cached(&cache, &hot_url));
+    }
+    store_body(&mut cache, &large_url, vec![0; 1020]);
+
+    (is_cached(&cache, &hot_url), is_cached(&cache, &large_url))
+}
+
+#[test]
+fn test_lfu_eviction_keeps_frequently_hit_resource() {
+    assert_eq!(evict_under_policy(EvictionPolicy::Lfu), (true, false));
+}
+
+#[test]
+fn test_lru_eviction_evicts_least_recently_used_resource() {
+    assert_eq!(evict_under_policy(EvictionPolicy::Lru), (false, true));
+}
+
+#[test]
+fn test_vary_star_response_is_not_stored() {
+    let url = ServoUrl::parse("https://servo.org/vary-star").unwrap();
+    let request = create_request(&url, HeaderMap::new());
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+    headers.insert(VARY, HeaderValue::from_static("*"));
+    let response = create_response(&url, headers, ResponseBody::Done(b"body".to_vec()));
+    let mut cache = HttpCache::default();
+    cache.store(&request, &response);
+
+    assert!(cache.iter_entries().next().is_none());
+}
+
+#[test]
+fn test_proxy_revalidate_overrides_max_stale_in_shared_mode() {
+    assert!(store_and_lookup(
+        StatusCode::OK,
+        header_map(&[(CACHE_CONTROL, "max-age=0, proxy-revalidate")]),
+        header_map(&[(CACHE_CONTROL, "max-stale=3600")]),
+        0,
+        |cache| cache.set_mode(HttpCacheMode::Shared),
+    ));
+}
+
+#[test]
+fn test_proxy_revalidate_is_ignored_in_private_mode() {
+    assert!(!store_and_lookup(
+        StatusCode::OK,
+        header_map(&[(CACHE_CONTROL, "max-age=0, proxy-revalidate")]),
+        header_map(&[(CACHE_CONTROL, "max-stale=3600")]),
+        0,
+        |cache| cache.set_mode(HttpCacheMode::Private),
+    ));
+}
+
+#[test]
+fn test_bodies_over_max_size_are_not_stored() {
+    let under_url = ServoUrl::parse("https://servo.org/under.bin").unwrap();
+    let over_url = ServoUrl::parse("https://servo.org/over.bin").unwrap();
+    let mut cache = HttpCache::default();
+    cache.set_max_body_bytes(Some(1024));
+
+    store_body(&mut cache, &under_url, vec![0; 1024]);
+    store_body(&mut cache, &over_url, vec![0; 1025]);
+
+    assert!(is_cached(&cache, &under_url));
+    assert!(!is_cached(&cache, &over_url));
+}
+
+#[test]
+fn test_content_length_over_max_size_is_not_stored() {
+    let url = ServoUrl::parse("https://servo.org/download.bin").unwrap();
+    let request = create_request(&url, HeaderMap::new());
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+    headers.insert(CONTENT_LENGTH, HeaderValue::from_static("4096"));
+    let response = create_response(&url, headers, ResponseBody::Receiving(vec![]));
+    let mut cache = HttpCache::default();
+    cache.set_max_body_bytes(Some(1024));
+    cache.store(&request, &response);
+
+    assert!(cache.iter_entries().next().is_none());
+}
+
+#[test]
+fn test_received_body_over_max_size_is_removed_on_completion() {
+    let url = ServoUrl::parse("https://servo.org/chunked.bin").unwrap();
+    let request = create_request(&url, HeaderMap::new());
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+    let response = create_response(&url, headers, ResponseBody::Receiving(vec![]));
+    let mut cache = HttpCache::default();
+    cache.set_max_body_bytes(Some(1024));
+    cache.store(&request, &response);
+    assert!(is_cached(&cache, &url));
+
+    *response.body.lock().unwrap() = ResponseBody::Done(vec![0; 4096]);
+    cache.update_awaiting_consumers(&request, &response);
+    assert!(cache.iter_entries().next().is_none());
+}
+
+#[test]
+fn test_exact_partial_range_shares_stored_body() {
+    let url = ServoUrl::parse("https://servo.org/media.webm").unwrap();
+    let mut range_headers = HeaderMap::new();
+    range_headers.insert(RANGE, HeaderValue::from_static("bytes=2-5"));
+    let request = create_request(&url, range_headers);
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+    headers.insert(CONTENT_RANGE, HeaderValue::from_static("bytes 2-5/10"));
+    let mut response = create_response(&url, headers, ResponseBody::Done(b"2345".to_vec()));
+    response.status = StatusCode::PARTIAL_CONTENT.into();
+    let mut cache = HttpCache::default();
+    cache.store(&request, &response);
+
+    let cached_response = cache.construct_response(&request, &mut None).unwrap();
+    assert!(ServoArc::ptr_eq(
+        &cached_response.response.body,
+        &response.body
+    ));
+
+    // A range within the stored partial is still sliced into a new body.
+    let mut range_headers = HeaderMap::new();
+    range_headers.insert(RANGE, HeaderValue::from_static("bytes=3-4"));
+    let request = create_request(&url, range_headers);
+    let cached_response = cache.construct_response(&request, &mut None).unwrap();
+    assert!(!ServoArc::ptr_eq(
+        &cached_response.response.body,
+        &response.body
+    ));
+    assert_eq!(
+        *cached_response.response.body.lock().unwrap(),
+        ResponseBody::Done(b"34".to_vec())
+    );
+}
+
+#[test]
+fn test_mock_clock_drives_resource_from_fresh_to_stale() {
+    let url = ServoUrl::parse("https://servo.org/clock").unwrap();
+    let request = create_request(&url, HeaderMap::new());
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=60"));
+    let response = create_response(&url, headers, ResponseBody::Done(b"body".to_vec()));
+    let clock = MockClock::default();
+    let mut cache = HttpCache::default();
+    cache.set_clock(Box::new(clock.clone()));
+    cache.store(&request, &response);
+
+    clock.advance(Duration::from_secs(59));
+    let cached_response = cache.construct_response(&request, &mut None).unwrap();
+    assert!(!cached_response.needs_validation);
+
+    clock.advance(Duration::from_secs(1));
+    let cached_response = cache.construct_response(&request, &mut None).unwrap();
+    assert!(cached_response.needs_validation);
+}
+
+#[test]
+fn test_request_no_cache_revalidates_with_stored_validators() {
+    let url = ServoUrl::parse("https://servo.org/reload").unwrap();
+    let request = create_request(&url, HeaderMap::new());
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+    headers.insert(ETAG, HeaderValue::from_static("\"v1\""));
+    let response = create_response(&url, headers, ResponseBody::Done(b"body".to_vec()));
+    let mut cache = HttpCache::default();
+    cache.store(&request, &response);
+
+    for (name, value) in [
+        (CACHE_CONTROL, "no-cache"),
+        (CACHE_CONTROL, "no-cache, max-stale=3600"),
+        (PRAGMA, "no-cache"),
+    ] {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_static(value));
+        let reload_request = create_request(&url, headers);
+        let cached_response = cache
+            .construct_response(&reload_request, &mut None)
+            .unwrap();
+        assert!(cached_response.needs_validation);
+        let conditional_headers = cache.conditional_headers_for(&reload_request).unwrap();
+        assert_eq!(
+            conditional_headers.get(IF_NONE_MATCH),
+            Some(&HeaderValue::from_static("\"v1\""))
+        );
+    }
+}
+
+fn store_partial(cache: &mut HttpCache, url: &ServoUrl, content_range: &'static str, body: &[u8]) {
+    let request = create_request(url, HeaderMap::new());
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+    headers.insert(CONTENT_RANGE, HeaderValue::from_static(content_range));
+    let mut response = create_response(url, headers, ResponseBody::Done(body.to_vec()));
+    response.status = StatusCode::PARTIAL_CONTENT.into();
+    cache.store(&request, &response);
+}
+
+#[test]
+fn test_partial_with_mismatched_content_range_is_not_stored() {
+    let url = ServoUrl::parse("https://servo.org/lying.bin").unwrap();
+    let mut cache = HttpCache::default();
+    store_partial(&mut cache, &url, "bytes 0-9/100", b"short");
+
+    assert!(cache.iter_entries().next().is_none());
+}
+
+#[test]
+fn test_partial_with_consistent_content_range_is_stored() {
+    let url = ServoUrl::parse("https://servo.org/honest.bin").unwrap();
+    let mut cache = HttpCache::default();
+    store_partial(&mut cache, &url, "bytes 0-4/100", b"exact");
+
+    assert_eq!(cache.iter_entries().count(), 1);
+}
+
+fn range_response(cache: &HttpCache, url: &ServoUrl, range: &'static str) -> Option<Response> {
+    let mut headers = HeaderMap::new();
+    headers.insert(RANGE, HeaderValue::from_static(range));
+    let request = create_request(url, headers);
+    cache
+        .construct_response(&request, &mut None)
+        .map(|cached_response| cached_response.response)
+}
+
+#[test]
+fn test_unsatisfiable_range_does_not_panic() {
+    let url = ServoUrl::parse("https://servo.org/small.bin").unwrap();
+    let mut cache = HttpCache::default();
+    store_body(&mut cache, &url, vec![0; 16]);
+
+    assert!(range_response(&cache, &url, "bytes=-2000").is_none());
+    assert!(range_response(&cache, &url, "bytes=a-b").is_none());
+}
+
+#[test]
+fn test_range_on_zero_length_body_does_not_panic() {
+    let url = ServoUrl::parse("https://servo.org/empty.bin").unwrap();
+    let mut cache = HttpCache::default();
+    store_body(&mut cache, &url, vec![]);
+
+    assert!(range_response(&cache, &url, "bytes=-1").is_none());
+    let response = range_response(&cache, &url, "bytes=0-").unwrap();
+    assert_eq!(response.status, StatusCode::RANGE_NOT_SATISFIABLE);
+}
+
+#[test]
+fn test_range_on_unsatisfied_partial_does_not_panic() {
+    let url = ServoUrl::parse("https://servo.org/unsatisfied.bin").unwrap();
+    let request = create_request(&url, HeaderMap::new());
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+    headers.insert(CONTENT_RANGE, HeaderValue::from_static("bytes */0"));
+    let mut response = create_response(&url, headers, ResponseBody::Receiving(vec![]));
+    response.status = StatusCode::PARTIAL_CONTENT.into();
+    let mut cache = HttpCache::default();
+    cache.store(&request, &response);
+
+    assert!(range_response(&cache, &url, "bytes=0-").is_none());
+}
+
+#[test]
+fn test_range_past_end_of_complete_body_is_not_satisfiable() {
+    let url = ServoUrl::parse("https://servo.org/kilobyte.bin").unwrap();
+    let mut cache = HttpCache::default();
+    store_body(&mut cache, &url, vec![0; 1024]);
+
+    let response = range_response(&cache, &url, "bytes=99999-").unwrap();
+    assert_eq!(response.status, StatusCode::RANGE_NOT_SATISFIABLE);
+    assert_eq!(
+        response.headers.get(CONTENT_RANGE),
+        Some(&HeaderValue::from_static("bytes */1024"))
+    );
+}
+
+#[test]
+fn test_range_is_served_from_the_tightest_covering_partial() {
+    let url = ServoUrl::parse("https://servo.org/overlapping.bin").unwrap();
+    let mut cache = HttpCache::default();
+    store_partial(&mut cache, &url, "bytes 0-9/100", b"aaaaaaaaaa");
+    store_partial(&mut cache, &url, "bytes 2-5/100", b"bbbb");
+
+    let response = range_response(&cache, &url, "bytes=3-4").unwrap();
+    assert_eq!(response.status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(
+        *response.body.lock().unwrap(),
+        ResponseBody::Done(b"bb".to_vec())
+    );
+}
+
+#[test]
+fn test_default_ttl_applies_to_responses_without_freshness_information() {
+    // Not cacheable by default, so that no heuristic freshness applies.
+    assert!(!store_and_lookup(
+        StatusCode::FOUND,
+        header_map(&[(ETAG, "\"api\"")]),
+        HeaderMap::new(),
+        0,
+        |cache| cache.set_default_ttl(Some(Duration::from_secs(300))),
+    ));
+}
+
+#[test]
+fn test_default_ttl_replaces_heuristic_freshness_without_last_modified() {
+    let with_default_ttl = |elapsed| {
+        store_and_lookup(
+            StatusCode::OK,
+            header_map(&[(ETAG, "\"api\"")]),
+            HeaderMap::new(),
+            elapsed,
+            |cache| cache.set_default_ttl(Some(Duration::from_secs(300))),
+        )
+    };
+    assert!(!with_default_ttl(200));
+    assert!(with_default_ttl(600));
+    // Without a default time-to-live, the heuristic of 24 hours applies.
+    assert!(!store_and_lookup(
+        StatusCode::OK,
+        header_map(&[(ETAG, "\"api\"")]),
+        HeaderMap::new(),
+        600,
+        |_| {},
+    ));
+}
+
+#[test]
+fn test_responses_without_freshness_information_need_validation_by_default() {
+    assert!(store_and_lookup(
+        StatusCode::FOUND,
+        header_map(&[(ETAG, "\"api\"")]),
+        HeaderMap::new(),
+        0,
+        |_| {},
+    ));
+}
+
+fn await_stored_body(
+    cache: &mut HttpCache,
+    request: &Request,
+    response: &Response,
+) -> Receiver<Data> {
+    cache.store(request, response);
+    let (send, recv) = unbounded();
+    let mut done_chan = Some((send, recv));
+    assert!(cache.construct_response(request, &mut done_chan).is_some());
+    done_chan.take().unwrap().1
+}
+
+#[test]
+fn test_empty_204_wakes_consumers_with_done() {
+    let url = ServoUrl::parse("https://servo.org/no-content").unwrap();
+    let request = create_request(&url, HeaderMap::new());
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+    let mut response = create_response(&url, headers, ResponseBody::Receiving(vec![]));
+    response.status = StatusCode::NO_CONTENT.into();
+    let mut cache = HttpCache::default();
+    let mut recv = await_stored_body(&mut cache, &request, &response);
+
+    // A 204 has a null body.
+    *response.body.lock().unwrap() = ResponseBody::Empty;
+    cache.update_awaiting_consumers(&request, &response);
+
+    assert!(matches!(recv.try_recv(), Ok(Data::Done)));
+    assert!(is_cached(&cache, &url));
+}
+
+#[test]
+fn test_failed_fetch_wakes_consumers_with_cancelled() {
+    let url = ServoUrl::parse("https://servo.org/failed").unwrap();
+    let request = create_request(&url, HeaderMap::new());
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+    let response = create_response(&url, headers, ResponseBody::Receiving(vec![]));
+    let mut cache = HttpCache::default();
+    let mut recv = await_stored_body(&mut cache, &request, &response);
+
+    *response.body.lock().unwrap() = ResponseBody::Empty;
+    let network_error = Response::network_error(NetworkError::Internal("Fetch failed".into()));
+    cache.update_awaiting_consumers(&request, &network_error);
+
+    assert!(matches!(recv.try_recv(), Ok(Data::Cancelled)));
+    assert!(!is_cached(&cache, &url));
+}
+
+#[test]
+fn test_prune_expired_keeps_revalidatable_and_fresh_resources() {
+    let mut cache = HttpCache::default();
+    for (path, cache_control, etag) in [
+        ("expired", "max-age=0", None),
+        ("expired-with-etag", "max-age=0", Some("\"v1\"")),
+        ("fresh", "max-age=3600", None),
+    ] {
+        let url = ServoUrl::parse("https://servo.org/")
+            .unwrap()
+            .join(path)
+            .unwrap();
+        let request = create_request(&url, HeaderMap::new());
+        let mut headers = HeaderMap::new();
+        headers.insert(CACHE_CONTROL, HeaderValue::from_static(cache_control));
+        if let Some(etag) = etag {
+            headers.insert(ETAG, HeaderValue::from_static(etag));
+        }
+        let response = create_response(&url, headers, ResponseBody::Done(b"body".to_vec()));
+        cache.store(&request, &response);
+    }
+
+    assert_eq!(cache.prune_expired(), 1);
+    let mut remaining: Vec<String> = cache
+        .iter_entries()
+        .map(|entry| entry.url.path().to_owned())
+        .collect();
+    remaining.sort();
+    assert_eq!(remaining, vec!["/expired-with-etag", "/fresh"]);
+}
+
+#[test]
+fn test_partial_without_content_range_is_not_cacheable() {
+    let url = ServoUrl::parse("https://servo.org/no-range.bin").unwrap();
+    let request = create_request(&url, HeaderMap::new());
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+    let mut response = create_response(&url, headers, ResponseBody::Receiving(vec![]));
+    response.status = StatusCode::PARTIAL_CONTENT.into();
+    let mut cache = HttpCache::default();
+    cache.store(&request, &response);
+    assert!(cache.iter_entries().next().is_none());
+
+    response
+        .headers
+        .insert(CONTENT_RANGE, HeaderValue::from_static("bytes 0-9/100"));
+    cache.store(&request, &response);
+    assert_eq!(cache.iter_entries().count(), 1);
+}
+
+#[test]
+fn test_stale_while_revalidate_requests_background_revalidation_once() {
+    let url = ServoUrl::parse("https://servo.org/swr").unwrap();
+    let request = create_request(&url, HeaderMap::new());
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        CACHE_CONTROL,
+        HeaderValue::from_static("max-age=60, stale-while-revalidate=600"),
+    );
+    let response = create_response(&url, headers, ResponseBody::Done(b"body".to_vec()));
+    let (revalidation_sender, mut revalidation_receiver) = unbounded();
+    let clock = MockClock::default();
+    let mut cache = HttpCache::new(Some(revalidation_sender));
+    cache.set_clock(Box::new(clock.clone()));
+    cache.store(&request, &response);
+
+    clock.advance(Duration::from_secs(120));
+    for _ in 0..2 {
+        let cached_response = cache.construct_response(&request, &mut None).unwrap();
+        assert!(!cached_response.needs_validation);
+    }
+
+    assert_eq!(revalidation_receiver.try_recv().ok(), Some(url));
+    assert!(revalidation_receiver.try_recv().is_err());
+
+    // Past the stale-while-revalidate window, the response must be validated.
+    clock.advance(Duration::from_secs(600));
+    let cached_response = cache.construct_response(&request, &mut None).unwrap();
+    assert!(cached_response.needs_validation);
+}
+
+#[test]
+fn test_stale_while_revalidate_needs_a_revalidation_channel() {
+    assert!(store_and_lookup(
+        StatusCode::OK,
+        header_map(&[(CACHE_CONTROL, "max-age=60, stale-while-revalidate=600")]),
+        HeaderMap::new(),
+        120,
+        |_| {},
+    ));
+}
+
+#[test]
+fn test_stale_while_revalidate_does_not_override_request_directives() {
+    let url = ServoUrl::parse("https://servo.org/swr").unwrap();
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        CACHE_CONTROL,
+        HeaderValue::from_static("max-age=100, stale-while-revalidate=60"),
+    );
+    let response = create_response(&url, headers, ResponseBody::Done(b"body".to_vec()));
+    let (revalidation_sender, mut revalidation_receiver) = unbounded();
+    let mut cache = HttpCache::new(Some(revalidation_sender));
+    cache.store(&create_request(&url, HeaderMap::new()), &response);
+
+    for request_cache_control in ["max-age=0", "min-fresh=200"] {
+        let mut request_headers = HeaderMap::new();
+        request_headers.insert(
+            CACHE_CONTROL,
+            HeaderValue::from_static(request_cache_control),
+        );
+        let request = create_request(&url, request_headers);
+        let cached_response = cache.construct_response(&request, &mut None).unwrap();
+        assert!(cached_response.needs_validation);
+    }
+    assert!(revalidation_receiver.try_recv().is_err());
+}
+
+#[test]
+fn test_request_max_age_zero_forces_revalidation() {
+    let url = ServoUrl::parse("https://servo.org/max-age-zero").unwrap();
+    let request = create_request(&url, HeaderMap::new());
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=100"));
+    let response = create_response(&url, headers, ResponseBody::Done(b"body".to_vec()));
+    let mut cache = HttpCache::default();
+    cache.store(&request, &response);
+
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=0"));
+    let reload_request = create_request(&url, headers);
+    let cached_response = cache
+        .construct_response(&reload_request, &mut None)
+        .unwrap();
+    assert!(cached_response.needs_validation);
+    assert_eq!(
+        *cached_response.response.body.lock().unwrap(),
+        ResponseBody::Done(b"body".to_vec())
+    );
+}
+
+#[test]
+fn test_prebuilt_resource_is_served() {
+    let url = ServoUrl::parse("https://servo.org/prebuilt.css").unwrap();
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("text/css"));
+    let mut cache = HttpCache::default();
+    cache.insert_prebuilt(url.clone(), headers, b"body {}".to_vec(), StatusCode::OK);
+
+    let request = create_request(&url, HeaderMap::new());
+    let cached_response = cache.construct_response(&request, &mut None).unwrap();
+    assert!(!cached_response.needs_validation);
+    assert_eq!(cached_response.response.status, StatusCode::OK);
+    assert_eq!(
+        *cached_response.response.body.lock().unwrap(),
+        ResponseBody::Done(b"body {}".to_vec())
+    );
+    let entry = cache.iter_entries().next().unwrap();
+    assert_eq!(entry.content_type.as_deref(), Some("text/css"));
+}
+
+#[test]
+fn test_prebuilt_resource_with_uncacheable_status_is_not_stored() {
+    let url = ServoUrl::parse("https://servo.org/prebuilt-error").unwrap();
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+    let mut cache = HttpCache::default();
+    cache.insert_prebuilt(url, headers, vec![], StatusCode::INTERNAL_SERVER_ERROR);
+
+    assert!(cache.iter_entries().next().is_none());
+}
+
+#[test]
+fn test_prebuilt_no_store_resource_is_not_stored() {
+    let url = ServoUrl::parse("https://servo.org/prebuilt-no-store").unwrap();
+    let mut cache = HttpCache::default();
+    cache.insert_prebuilt(
+        url,
+        header_map(&[(CACHE_CONTROL, "no-store")]),
+        b"body".to_vec(),
+        StatusCode::OK,
+    );
+
+    assert!(cache.iter_entries().next().is_none());
+}
+
+#[test]
+fn test_request_max_age_bounds_fresh_resource() {
+    let needs_validation = |elapsed| {
+        store_and_lookup(
+            StatusCode::OK,
+            header_map(&[(CACHE_CONTROL, "max-age=3600")]),
+            header_map(&[(CACHE_CONTROL, "max-age=600, min-fresh=300")]),
+            elapsed,
+            |_| {},
+        )
+    };
+    assert!(!needs_validation(599));
+    assert!(needs_validation(600));
+}
+
+#[test]
+fn test_request_min_fresh_applies_alongside_max_age() {
+    let needs_validation = |elapsed| {
+        store_and_lookup(
+            StatusCode::OK,
+            header_map(&[(CACHE_CONTROL, "max-age=700")]),
+            header_map(&[(CACHE_CONTROL, "max-age=600, min-fresh=300")]),
+            elapsed,
+            |_| {},
+        )
+    };
+    assert!(!needs_validation(399));
+    assert!(needs_validation(400));
+}
+
+#[test]
+fn test_request_max_age_bounds_max_stale() {
+    let needs_validation = |elapsed| {
+        store_and_lookup(
+            StatusCode::OK,
+            header_map(&[(CACHE_CONTROL, "max-age=60")]),
+            header_map(&[(CACHE_CONTROL, "max-stale=600, max-age=120")]),
+            elapsed,
+            |_| {},
+        )
+    };
+    assert!(!needs_validation(119));
+    assert!(needs_validation(120));
+}
+
+#[test]
+fn test_hop_by_hop_headers_are_not_served_from_cache() {
+    let url = ServoUrl::parse("https://servo.org/hop-by-hop").unwrap();
+    let request = create_request(&url, HeaderMap::new());
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+    headers.insert(CONNECTION, HeaderValue::from_static("close, X-Foo"));
+    headers.insert(
+        HeaderName::from_static("keep-alive"),
+        HeaderValue::from_static("timeout=5"),
+    );
+    headers.insert(TRANSFER_ENCODING, HeaderValue::from_static("chunked"));
+    headers.insert(
+        HeaderName::from_static("x-foo"),
+        HeaderValue::from_static("bar"),
+    );
+    headers.insert(
+        HeaderName::from_static("x-bar"),
+        HeaderValue::from_static("baz"),
+    );
+    let response = create_response(&url, headers, ResponseBody::Done(b"body".to_vec()));
+    let mut cache = HttpCache::default();
+    cache.store(&request, &response);
+
+    let cached_response = cache.construct_response(&request, &mut None).unwrap();
+    let served_headers = &cached_response.response.headers;
+    assert!(!served_headers.contains_key(CONNECTION));
+    assert!(!served_headers.contains_key("keep-alive"));
+    assert!(!served_headers.contains_key(TRANSFER_ENCODING));
+    assert!(!served_headers.contains_key("x-foo"));
+    assert_eq!(served_headers.get("x-bar").unwrap(), "baz");
+    assert!(served_headers.contains_key(CACHE_CONTROL));
+}
+
+#[test]
+fn test_request_no_store_bypasses_fresh_resource() {
+    let url = ServoUrl::parse("https://servo.org/private").unwrap();
+    let request = create_request(&url, HeaderMap::new());
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+    let response = create_response(&url, headers, ResponseBody::Done(b"body".to_vec()));
+    let mut cache = HttpCache::default();
+    cache.store(&request, &response);
+
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    let no_store_request = create_request(&url, headers);
+    assert!(
+        cache
+            .construct_response(&no_store_request, &mut None)
+            .is_none()
+    );
+
+    // The stored resource is left untouched for other requests.
+    assert!(cache.construct_response(&request, &mut None).is_some());
+    assert_eq!(cache.iter_entries().next().unwrap().hit_count, 1);
+}
+
+#[test]
+fn test_variants_per_key_are_capped() {
+    let url = ServoUrl::parse("https://servo.org/varies").unwrap();
+    let user_agent = HeaderName::from_static("user-agent");
+    let clock = MockClock::default();
+    let mut cache = HttpCache::default();
+    cache.set_clock(Box::new(clock.clone()));
+    cache.set_max_variants_per_key(3);
+    let requests: Vec<Request> = (0..5)
+        .map(|index| {
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                user_agent.clone(),
+                HeaderValue::from_str(&format!("agent-{index}")).unwrap(),
+            );
+            create_request(&url, headers)
+        })
+        .collect();
+    for request in &requests {
+        let mut headers = HeaderMap::new();
+        headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+        headers.insert(VARY, HeaderValue::from_static("User-Agent"));
+        let response = create_response(&url, headers, ResponseBody::Done(b"body".to_vec()));
+        cache.store(request, &response);
+        clock.advance(Duration::from_secs(1));
+        assert!(cache.iter_entries().count() <= 3);
+    }
+
+    assert_eq!(cache.iter_entries().count(), 3);
+    assert!(cache.construct_response(&requests[0], &mut None).is_none());
+    assert!(cache.construct_response(&requests[1], &mut None).is_none());
+    for request in &requests[2..] {
+        assert!(cache.construct_response(request, &mut None).is_some());
+    }
+}
+
+#[test]
+fn test_expire_all_forces_validation_of_every_resource() {
+    let mut cache = HttpCache::default();
+    let requests: Vec<Request> = ["first", "second", "third"]
+        .iter()
+        .map(|path| {
+            let url = ServoUrl::parse(&format!("https://servo.org/{path}")).unwrap();
+            let request = create_request(&url, HeaderMap::new());
+            let mut headers = HeaderMap::new();
+            headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+            headers.insert(ETAG, HeaderValue::from_static("\"v1\""));
+            let response = create_response(&url, headers, ResponseBody::Done(b"body".to_vec()));
+            cache.store(&request, &response);
+            request
+        })
+        .collect();
+
+    cache.expire_all();
+    for request in &requests {
+        let cached_response = cache.construct_response(request, &mut None).unwrap();
+        assert!(cached_response.needs_validation);
+        assert_eq!(
+            *cached_response.response.body.lock().unwrap(),
+            ResponseBody::Done(b"body".to_vec())
+        );
+        let conditional_headers = cache.conditional_headers_for(request).unwrap();
+        assert_eq!(conditional_headers.get(IF_NONE_MATCH).unwrap(), "\"v1\"");
+    }
+}
+
+#[test]
+fn test_range_responses_carry_the_context_of_the_complete_resource() {
+    let original_url = ServoUrl::parse("http://servo.org/moved.bin").unwrap();
+    let url = ServoUrl::parse("https://servo.org/range-context.bin").unwrap();
+    let request = create_request(&url, HeaderMap::new());
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+    let mut response = create_response(&url, headers, ResponseBody::Done(b"0123456789".to_vec()));
+    response.url_list = vec![original_url, url.clone()];
+    response.location_url = Some(Ok(url.clone()));
+    response.https_state = HttpsState::Modern;
+    let mut cache = HttpCache::default();
+    cache.store(&request, &response);
+
+    let complete_response = cache
+        .construct_response(&request, &mut None)
+        .unwrap()
+        .response;
+    for range in ["bytes=0-3", "bytes=4-", "bytes=-2", "bytes=100-"] {
+        let range_response = range_response(&cache, &url, range).unwrap();
+        assert_eq!(range_response.url_list, complete_response.url_list);
+        assert_eq!(range_response.location_url, complete_response.location_url);
+        assert_eq!(range_response.https_state, HttpsState::Modern);
+    }
+}
+
+#[test]
+fn test_stale_while_revalidate_applies_without_freshness_lifetime() {
+    let url = ServoUrl::parse("https://servo.org/always-revalidated").unwrap();
+    let request = create_request(&url, HeaderMap::new());
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        CACHE_CONTROL,
+        HeaderValue::from_static("max-age=0, stale-while-revalidate=60"),
+    );
+    let response = create_response(&url, headers, ResponseBody::Done(b"body".to_vec()));
+    let (revalidation_sender, mut revalidation_receiver) = unbounded();
+    let clock = MockClock::default();
+    let mut cache = HttpCache::new(Some(revalidation_sender));
+    cache.set_clock(Box::new(clock.clone()));
+    cache.store(&request, &response);
+
+    let cached_response = cache.construct_response(&request, &mut None).unwrap();
+    assert!(!cached_response.needs_validation);
+    assert_eq!(
+        *cached_response.response.body.lock().unwrap(),
+        ResponseBody::Done(b"body".to_vec())
+    );
+    assert_eq!(revalidation_receiver.try_recv().ok(), Some(url));
+
+    clock.advance(Duration::from_secs(60));
+    let cached_response = cache.construct_response(&request, &mut None).unwrap();
+    assert!(cached_response.needs_validation);
+}
+
+#[test]
+fn test_age_header_with_multiple_values_uses_the_first() {
+    let needs_validation = |elapsed| {
+        store_and_lookup(
+            StatusCode::OK,
+            header_map(&[(CACHE_CONTROL, "max-age=3600"), (AGE, "100, 200")]),
+            HeaderMap::new(),
+            elapsed,
+            |_| {},
+        )
+    };
+    assert!(!needs_validation(3499));
+    assert!(needs_validation(3500));
+}
+
+#[test]
+fn test_age_header_with_fractional_value_is_floored() {
+    let needs_validation = |elapsed| {
+        store_and_lookup(
+            StatusCode::OK,
+            header_map(&[(CACHE_CONTROL, "max-age=3600"), (AGE, "100.9")]),
+            HeaderMap::new(),
+            elapsed,
+            |_| {},
+        )
+    };
+    assert!(!needs_validation(3499));
+    assert!(needs_validation(3500));
+}
+
+#[test]
+fn test_enormous_age_header_is_clamped() {
+    let needs_validation = |elapsed| {
+        store_and_lookup(
+            StatusCode::OK,
+            header_map(&[
+                (CACHE_CONTROL, "max-age=3600"),
+                (AGE, "99999999999999999999999999"),
+            ]),
+            HeaderMap::new(),
+            elapsed,
+            |_| {},
+        )
+    };
+    assert!(needs_validation(0));
+}
+
+#[test]
+fn test_malformed_age_header_is_ignored() {
+    let needs_validation = |elapsed| {
+        store_and_lookup(
+            StatusCode::OK,
+            header_map(&[(CACHE_CONTROL, "max-age=3600"), (AGE, "soon")]),
+            HeaderMap::new(),
+            elapsed,
+            |_| {},
+        )
+    };
+    assert!(!needs_validation(3599));
+    assert!(needs_validation(3600));
+}
+
+#[test]
+fn test_eviction_callback_reports_evicted_resources() {
+    let first_url = ServoUrl::parse("https://servo.org/first.bin").unwrap();
+    let second_url = ServoUrl::parse("https://servo.org/second.bin").unwrap();
+    let evictions = Arc::new(Mutex::new(vec![]));
+    let recorded_evictions = evictions.clone();
+    let mut cache = HttpCache::default();
+    cache.set_on_evict(Some(Box::new(move |url, size| {
+        recorded_evictions.lock().unwrap().push((url.clone(), size));
+    })));
+    cache.set_memory_budget(Some(1024));
+
+    store_body(&mut cache, &first_url, vec![0; 600]);
+    assert!(evictions.lock().unwrap().is_empty());
+    store_body(&mut cache, &second_url, vec![0; 600]);
+
+    assert_eq!(*evictions.lock().unwrap(), vec![(first_url.clone(), 600)]);
+    assert!(!is_cached(&cache, &first_url));
+    assert!(is_cached(&cache, &second_url));
+}
+
+#[test]
+fn test_eviction_callback_reports_pruned_resources() {
+    let url = ServoUrl::parse("https://servo.org/pruned.bin").unwrap();
+    let request = create_request(&url, HeaderMap::new());
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=0"));
+    let response = create_response(&url, headers, ResponseBody::Done(vec![0; 16]));
+    let evictions = Arc::new(Mutex::new(vec![]));
+    let recorded_evictions = evictions.clone();
+    let mut cache = HttpCache::default();
+    cache.set_on_evict(Some(Box::new(move |url, size| {
+        recorded_evictions.lock().unwrap().push((url.clone(), size));
+    })));
+    cache.store(&request, &response);
+
+    assert_eq!(cache.prune_expired(), 1);
+    assert_eq!(*evictions.lock().unwrap(), vec![(url, 16)]);
+}
+
+#[test]
+fn test_eviction_callback_reports_resources_removed_by_retain() {
+    let kept_url = ServoUrl::parse("https://servo.org/kept.bin").unwrap();
+    let removed_url = ServoUrl::parse("https://example.com/removed.bin").unwrap();
+    let evictions = Arc::new(Mutex::new(vec![]));
+    let recorded_evictions = evictions.clone();
+    let mut cache = HttpCache::default();
+    cache.set_on_evict(Some(Box::new(move |url, size| {
+        recorded_evictions.lock().unwrap().push((url.clone(), size));
+    })));
+    store_body(&mut cache, &kept_url, vec![0; 16]);
+    store_body(&mut cache, &removed_url, vec![0; 32]);
+
+    cache.retain(|url, _| url.origin() == kept_url.origin());
+
+    assert_eq!(*evictions.lock().unwrap(), vec![(removed_url, 32)]);
+    assert!(is_cached(&cache, &kept_url));
+}
+
+fn refresh_etagged_response(request_etag: Option<&'static str>) -> Response {
+    let url = ServoUrl::parse("https://servo.org/conditional").unwrap();
+    let request = create_request(&url, HeaderMap::new());
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=0"));
+    headers.insert(ETAG, HeaderValue::from_static("\"v1\""));
+    let response = create_response(&url, headers, ResponseBody::Done(b"body".to_vec()));
+    let mut cache = HttpCache::default();
+    cache.store(&request, &response);
+
+    let mut headers = HeaderMap::new();
+    if let Some(request_etag) = request_etag {
+        headers.insert(IF_NONE_MATCH, HeaderValue::from_static(request_etag));
+    }
+    let conditional_request = create_request(&url, headers);
+    let mut not_modified_headers = HeaderMap::new();
+    not_modified_headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+    let mut not_modified = create_response(&url, not_modified_headers, ResponseBody::Empty);
+    not_modified.status = StatusCode::NOT_MODIFIED.into();
+    cache
+        .refresh_conditional(&conditional_request, not_modified, &mut None)
+        .unwrap()
+}
+
+#[test]
+fn test_refresh_conditional_passes_not_modified_through_to_matching_client() {
+    let refreshed_response = refresh_etagged_response(Some("\"v1\""));
+    assert_eq!(refreshed_response.status, StatusCode::NOT_MODIFIED);
+    assert_eq!(
+        *refreshed_response.body.lock().unwrap(),
+        ResponseBody::Empty
+    );
+    assert_eq!(
+        refreshed_response.headers.get(CACHE_CONTROL).unwrap(),
+        "max-age=3600"
+    );
+}
+
+#[test]
+fn test_refresh_conditional_returns_full_response_otherwise() {
+    for request_etag in [None, Some("\"v0\"")] {
+        let refreshed_response = refresh_etagged_response(request_etag);
+        assert_eq!(refreshed_response.status, StatusCode::OK);
+        assert_eq!(
+            *refreshed_response.body.lock().unwrap(),
+            ResponseBody::Done(b"body".to_vec())
+        );
+    }
+}
+
+#[test]
+fn test_range_within_received_bytes_is_served_from_receiving_body() {
+    let url = ServoUrl::parse("https://servo.org/streaming.webm").unwrap();
+    let request = create_request(&url, HeaderMap::new());
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+    let received: Vec<u8> = (0..1000).map(|index| (index % 251) as u8).collect();
+    let response = create_response(&url, headers, ResponseBody::Receiving(received.clone()));
+    let mut cache = HttpCache::default();
+    cache.store(&request, &response);
+
+    let partial_response = range_response(&cache, &url, "bytes=0-499").unwrap();
+    assert_eq!(partial_response.status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(
+        *partial_response.body.lock().unwrap(),
+        ResponseBody::Done(received[..500].to_vec())
+    );
+
+    // The bytes past those received, or relative to the unknown end, can't be served yet.
+    assert!(range_response(&cache, &url, "bytes=500-1499").is_none());
+    assert!(range_response(&cache, &url, "bytes=500-").is_none());
+    assert!(range_response(&cache, &url, "bytes=-100").is_none());
+}
+
+fn permanent_redirect_cache(extra_cacheable_status: &[StatusCode]) -> (HttpCache, Request) {
+    let url = ServoUrl::parse("https://servo.org/permanently-moved").unwrap();
+    let request = create_request(&url, HeaderMap::new());
+    let mut cache = HttpCache::default();
+    cache.set_extra_cacheable_status(extra_cacheable_status.iter().cloned().collect());
+    // Only cacheable when the status is understood.
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        CACHE_CONTROL,
+        HeaderValue::from_static("must-understand, no-store, max-age=3600"),
+    );
+    let mut response = create_response(&url, headers, ResponseBody::Done(vec![]));
+    response.status = StatusCode::PERMANENT_REDIRECT.into();
+    cache.store(&request, &response);
+    (cache, request)
+}
+
+#[test]
+fn test_permanent_redirect_is_not_cached_by_default() {
+    let (cache, request) = permanent_redirect_cache(&[]);
+    assert!(cache.construct_response(&request, &mut None).is_none());
+}
+
+#[test]
+fn test_extra_cacheable_status_is_cached() {
+    let (cache, request) = permanent_redirect_cache(&[StatusCode::PERMANENT_REDIRECT]);
+    let cached_response = cache.construct_response(&request, &mut None).unwrap();
+    assert!(!cached_response.needs_validation);
+    assert_eq!(
+        cached_response.response.status,
+        StatusCode::PERMANENT_REDIRECT
+    );
+}
+
+#[test]
+fn test_extra_cacheable_status_uses_heuristic_freshness() {
+    let url = ServoUrl::parse("https://servo.org/heuristic-redirect").unwrap();
+    let request = create_request(&url, HeaderMap::new());
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        LAST_MODIFIED,
+        HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"),
+    );
+    let mut response = create_response(&url, headers, ResponseBody::Done(vec![]));
+    response.status = StatusCode::PERMANENT_REDIRECT.into();
+
+    let mut cache = HttpCache::default();
+    cache.store(&request, &response);
+    let cached_response = cache.construct_response(&request, &mut None).unwrap();
+    assert!(cached_response.needs_validation);
+
+    let mut cache = HttpCache::default();
+    cache.set_extra_cacheable_status([StatusCode::PERMANENT_REDIRECT].into_iter().collect());
+    cache.store(&request, &response);
+    let cached_response = cache.construct_response(&request, &mut None).unwrap();
+    assert!(!cached_response.needs_validation);
+}
+
+#[test]
+fn test_trailers_are_served_with_cached_response() {
+    let url = ServoUrl::parse("https://servo.org/timed").unwrap();
+    let request = create_request(&url, HeaderMap::new());
+    let server_timing = HeaderName::from_static("server-timing");
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+    headers.insert(TRAILER, HeaderValue::from_static("Server-Timing"));
+    let response = create_response(&url, headers, ResponseBody::Receiving(b"bo".to_vec()));
+    let mut cache = HttpCache::default();
+    cache.store(&request, &response);
+
+    let mut trailers = HeaderMap::new();
+    trailers.insert(server_timing.clone(), HeaderValue::from_static("db;dur=53"));
+    cache.store_trailers(&request, trailers);
+    *response.body.lock().unwrap() = ResponseBody::Done(b"body".to_vec());
+    cache.update_awaiting_consumers(&request, &response);
+
+    let cached_response = cache.construct_response(&request, &mut None).unwrap();
+    let served_headers = &cached_response.response.headers;
+    assert_eq!(served_headers.get(TRAILER).unwrap(), "Server-Timing");
+    assert_eq!(served_headers.get(&server_timing).unwrap(), "db;dur=53");
+}
+
+#[test]
+fn test_freshness_remaining() {
+    let fresh_url = ServoUrl::parse("https://servo.org/fresh").unwrap();
+    let expired_url = ServoUrl::parse("https://servo.org/expired").unwrap();
+    let missing_url = ServoUrl::parse("https://servo.org/missing").unwrap();
+    let clock = MockClock::default();
+    let mut cache = HttpCache::default();
+    cache.set_clock(Box::new(clock.clone()));
+    for (url, directives) in [(&fresh_url, "max-age=3600"), (&expired_url, "max-age=60")] {
+        let request = create_request(url, HeaderMap::new());
+        let mut headers = HeaderMap::new();
+        headers.insert(CACHE_CONTROL, HeaderValue::from_static(directives));
+        let response = create_response(url, headers, ResponseBody::Done(b"body".to_vec()));
+        cache.store(&request, &response);
+    }
+
+    clock.advance(Duration::from_secs(600));
+    let freshness_remaining =
+        |url: &ServoUrl| cache.freshness_remaining(&create_request(url, HeaderMap::new()));
+    assert_eq!(
+        freshness_remaining(&fresh_url),
+        Some(Duration::from_secs(3000))
+    );
+    assert_eq!(freshness_remaining(&expired_url), Some(Duration::ZERO));
+    assert_eq!(freshness_remaining(&missing_url), None);
+}
+
+#[test]
+fn test_accept_ranges_is_advertised_on_full_and_range_responses() {
+    let url = ServoUrl::parse("https://servo.org/seekable.webm").unwrap();
+    let request = create_request(&url, HeaderMap::new());
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+    headers.insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    let response = create_response(&url, headers, ResponseBody::Done(b"0123456789".to_vec()));
+    let mut cache = HttpCache::default();
+    cache.store(&request, &response);
+
+    let full_response = cache
+        .construct_response(&request, &mut None)
+        .unwrap()
+        .response;
+    assert_eq!(full_response.headers.get(ACCEPT_RANGES).unwrap(), "bytes");
+    let partial_response = range_response(&cache, &url, "bytes=2-5").unwrap();
+    assert_eq!(partial_response.status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(
+        partial_response.headers.get(ACCEPT_RANGES).unwrap(),
+        "bytes"
+    );
+}
+
+#[test]
+fn test_accept_ranges_is_added_to_synthesized_range_responses() {
+    let url = ServoUrl::parse("https://servo.org/unadvertised.bin").unwrap();
+    let mut cache = HttpCache::default();
+    store_body(&mut cache, &url, b"0123456789".to_vec());
+
+    for range in ["bytes=2-5", "bytes=5-"] {
+        let partial_response = range_response(&cache, &url, range).unwrap();
+        assert_eq!(
+            partial_response.headers.get(ACCEPT_RANGES).unwrap(),
+            "bytes"
+        );
+    }
+}
+
+#[test]
+fn test_explain_missing_and_fresh_resources() {
+    let url = ServoUrl::parse("https://servo.org/explained").unwrap();
+    let request = create_request(&url, HeaderMap::new());
+    let mut cache = HttpCache::default();
+    assert!(cache.construct_response(&request, &mut None).is_none());
+    assert_eq!(cache.explain(&request), CacheLookupExplanation::NoEntry);
+
+    store_body(&mut cache, &url, b"body".to_vec());
+    assert_eq!(cache.explain(&request), CacheLookupExplanation::Hit);
+    // Explaining a lookup doesn't count as serving the resource.
+    assert_eq!(cache.iter_entries().next().unwrap().hit_count, 0);
+
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    assert_eq!(
+        cache.explain(&create_request(&url, headers)),
+        CacheLookupExplanation::NoStoreRequest
+    );
+}
+
+#[test]
+fn test_explain_expired_resource() {
+    let url = ServoUrl::parse("https://servo.org/explained-expired").unwrap();
+    let request = create_request(&url, HeaderMap::new());
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=0"));
+    let response = create_response(&url, headers, ResponseBody::Done(b"body".to_vec()));
+    let mut cache = HttpCache::default();
+    cache.store(&request, &response);
+
+    assert_eq!(
+        cache.explain(&request),
+        CacheLookupExplanation::NeedsValidation
+    );
+}
+
+#[test]
+fn test_explain_vary_mismatch() {
+    let url = ServoUrl::parse("https://servo.org/explained-vary").unwrap();
+    let mut headers = HeaderMap::new();
+    headers.insert(ACCEPT_ENCODING, HeaderValue::from_static("gzip"));
+    let request = create_request(&url, headers);
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+    headers.insert(VARY, HeaderValue::from_static("Accept-Encoding"));
+    let response = create_response(&url, headers, ResponseBody::Done(b"body".to_vec()));
+    let mut cache = HttpCache::default();
+    cache.store(&request, &response);
+
+    let mut headers = HeaderMap::new();
+    headers.insert(ACCEPT_ENCODING, HeaderValue::from_static("br"));
+    assert_eq!(
+        cache.explain(&create_request(&url, headers)),
+        CacheLookupExplanation::VaryMismatch("accept-encoding".to_owned())
+    );
+}
+
+#[test]
+fn test_explain_aborted_resource() {
+    let url = ServoUrl::parse("https://servo.org/explained-aborted").unwrap();
+    let request = create_request(&url, HeaderMap::new());
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+    let response = create_response(&url, headers, ResponseBody::Receiving(vec![]));
+    let mut cache = HttpCache::default();
+    cache.store(&request, &response);
+    response.aborted.store(true, Ordering::Release);
+
+    assert!(cache.construct_response(&request, &mut None).is_none());
+    assert_eq!(cache.explain(&request), CacheLookupExplanation::Aborted);
+}
+
+#[test]
+fn test_explain_only_partial_content() {
+    let url = ServoUrl::parse("https://servo.org/explained-partial").unwrap();
+    let mut cache = HttpCache::default();
+    store_partial(&mut cache, &url, "bytes 0-3/10", b"0123");
+
+    let request = create_request(&url, HeaderMap::new());
+    assert_eq!(
+        cache.explain(&request),
+        CacheLookupExplanation::OnlyPartialContent
+    );
+    let mut headers = HeaderMap::new();
+    headers.insert(RANGE, HeaderValue::from_static("bytes=0-1"));
+    assert_eq!(
+        cache.explain(&create_request(&url, headers)),
+        CacheLookupExplanation::Hit
+    );
+}
+
+#[test]
+fn test_no_transform_gzipped_resource_is_not_decoded() {
+    let url = ServoUrl::parse("https://servo.org/gzipped-no-transform").unwrap();
+    let mut cache = HttpCache::default();
+    store_gzipped_response(&mut cache, &url, "max-age=3600, no-transform", None);
+
+    let mut headers = HeaderMap::new();
+    headers.insert(ACCEPT_ENCODING, HeaderValue::from_static("identity"));
+    let request = create_request(&url, headers);
+    assert!(cache.construct_response(&request, &mut None).is_none());
+    assert_eq!(cache.explain(&request), CacheLookupExplanation::NoTransform);
+
+    // Clients accepting the stored coding are still served the stored bytes.
+    let mut headers = HeaderMap::new();
+    headers.insert(ACCEPT_ENCODING, HeaderValue::from_static("x-gzip"));
+    let request = create_request(&url, headers);
+    assert_eq!(cache.explain(&request), CacheLookupExplanation::Hit);
+    let cached_response = cache.construct_response(&request, &mut None).unwrap();
+    assert_eq!(
+        cached_response
+            .response
+            .headers
+            .get(CONTENT_ENCODING)
+            .unwrap(),
+        "x-gzip"
+    );
+}
+
+#[test]
+fn test_no_transform_body_decoded_by_the_fetch_layer_is_served() {
+    let url = ServoUrl::parse("https://servo.org/decoded-no-transform").unwrap();
+    let request = create_request(&url, HeaderMap::new());
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        CACHE_CONTROL,
+        HeaderValue::from_static("max-age=3600, no-transform"),
+    );
+    headers.insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+    let response = create_response(&url, headers, ResponseBody::Done(b"decoded".to_vec()));
+    let mut cache = HttpCache::default();
+    cache.store(&request, &response);
+
+    let mut headers = HeaderMap::new();
+    headers.insert(ACCEPT_ENCODING, HeaderValue::from_static("identity"));
+    let request = create_request(&url, headers);
+    let cached_response = cache.construct_response(&request, &mut None).unwrap();
+    assert_eq!(
+        *cached_response.response.body.lock().unwrap(),
+        ResponseBody::Done(b"decoded".to_vec())
+    );
+}
+
+#[test]
+fn test_resources_older_than_max_entry_age_are_not_served() {
+    let url = ServoUrl::parse("https://servo.org/long-lived").unwrap();
+    let request = create_request(&url, HeaderMap::new());
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=31536000"));
+    headers.insert(ETAG, HeaderValue::from_static("\"v1\""));
+    let response = create_response(&url, headers, ResponseBody::Done(b"body".to_vec()));
+    let week = Duration::from_secs(7 * 24 * 60 * 60);
+    let clock = MockClock::default();
+    let mut cache = HttpCache::default();
+    cache.set_clock(Box::new(clock.clone()));
+    cache.set_max_entry_age(Some(week));
+    cache.store(&request, &response);
+
+    clock.advance(week);
+    let cached_response = cache.construct_response(&request, &mut None).unwrap();
+    assert!(!cached_response.needs_validation);
+
+    clock.advance(Duration::from_secs(1));
+    assert!(cache.construct_response(&request, &mut None).is_none());
+    assert_eq!(cache.explain(&request), CacheLookupExplanation::NoEntry);
+    // Nor is it revalidated.
+    assert!(cache.conditional_headers_for(&request).is_none());
+    let mut not_modified = create_response(&url, HeaderMap::new(), ResponseBody::Empty);
+    not_modified.status = StatusCode::NOT_MODIFIED.into();
+    assert!(cache.refresh(&request, not_modified, &mut None).is_none());
+    assert_eq!(cache.prune_expired(), 1);
+    assert!(cache.iter_entries().next().is_none());
+}
+
+#[test]
+fn test_head_resource_is_not_served_to_get_request() {
+    let url = ServoUrl::parse("https://servo.org/headed").unwrap();
+    let mut head_request = create_request(&url, HeaderMap::new());
+    head_request.method = Method::HEAD;
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+    headers.insert(ETAG, HeaderValue::from_static("\"v1\""));
+    let response = create_response(&url, headers, ResponseBody::Empty);
+    let mut cache = HttpCache::default();
+    cache.store(&head_request, &response);
+    assert!(cache.iter_entries().next().is_none());
+
+    let request = create_request(&url, HeaderMap::new());
+    assert!(cache.construct_response(&request, &mut None).is_none());
+    assert_eq!(cache.explain(&request), CacheLookupExplanation::NoEntry);
+    assert!(cache.conditional_headers_for(&request).is_none());
+}
+
+#[test]
+fn test_get_resource_is_not_refreshed_for_head_request() {
+    let url = ServoUrl::parse("https://servo.org/headed").unwrap();
+    let request = create_request(&url, HeaderMap::new());
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=0"));
+    headers.insert(ETAG, HeaderValue::from_static("\"v1\""));
+    let response = create_response(&url, headers, ResponseBody::Done(b"body".to_vec()));
+    let mut cache = HttpCache::default();
+    cache.store(&request, &response);
+
+    let mut head_request = create_request(&url, HeaderMap::new());
+    head_request.method = Method::HEAD;
+    assert!(cache.conditional_headers_for(&head_request).is_none());
+    let mut not_modified_headers = HeaderMap::new();
+    not_modified_headers.insert(ETAG, HeaderValue::from_static("\"v1\""));
+    let mut not_modified = create_response(&url, not_modified_headers, ResponseBody::Empty);
+    not_modified.status = StatusCode::NOT_MODIFIED.into();
+    assert!(
+        cache
+            .refresh(&head_request, not_modified, &mut None)
+            .is_none()
+    );
+}
+
+fn accept_encoding_variant_matches(stored: &'static str, current: &'static str) -> bool {
+    let url = ServoUrl::parse("https://servo.org/negotiated").unwrap();
+    let mut headers = HeaderMap::new();
+    headers.insert(ACCEPT_ENCODING, HeaderValue::from_static(stored));
+    let request = create_request(&url, headers);
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+    headers.insert(VARY, HeaderValue::from_static("Accept-Encoding"));
+    let response = create_response(&url, headers, ResponseBody::Done(b"body".to_vec()));
+    let mut cache = HttpCache::default();
+    cache.store(&request, &response);
+
+    let mut headers = HeaderMap::new();
+    headers.insert(ACCEPT_ENCODING, HeaderValue::from_static(current));
+    let request = create_request(&url, headers);
+    cache.construct_response(&request, &mut None).is_some()
+}
+
+#[test]
+fn test_vary_accept_encoding_ignores_order_and_weights() {
+    assert!(accept_encoding_variant_matches(
+        "gzip, deflate",
+        "deflate, gzip"
+    ));
+    assert!(accept_encoding_variant_matches(
+        "gzip, deflate, br",
+        "br;q=0.5, GZIP, deflate;q=0.8"
+    ));
+}
+
+#[test]
+fn test_vary_accept_encoding_compares_accepted_codings() {
+    assert!(!accept_encoding_variant_matches("gzip", "identity"));
+    assert!(!accept_encoding_variant_matches("gzip, br", "gzip, br;q=0"));
+}
+
+#[test]
+fn test_expires_freshness_accounts_for_apparent_age() {
+    // The dates are rounded down to the second, and the clock of the cache starts a bit
+    // later, so the apparent age exceeds 3000 seconds by less than one.
+    let now = SystemTime::now();
+    let needs_validation = |elapsed| {
+        let mut headers = HeaderMap::new();
+        headers.typed_insert(Date::from(now - Duration::from_secs(3000)));
+        headers.typed_insert(Expires::from(now + Duration::from_secs(600)));
+        store_and_lookup(StatusCode::OK, headers, HeaderMap::new(), elapsed, |_| {})
+    };
+    assert!(!needs_validation(598));
+    assert!(needs_validation(600));
+}
+
+#[test]
+fn test_expires_freshness_accounts_for_age_header() {
+    let now = SystemTime::now();
+    let needs_validation = |elapsed| {
+        let mut headers = header_map(&[(AGE, "3000")]);
+        headers.typed_insert(Date::from(now));
+        headers.typed_insert(Expires::from(now + Duration::from_secs(3600)));
+        store_and_lookup(StatusCode::OK, headers, HeaderMap::new(), elapsed, |_| {})
+    };
+    assert!(!needs_validation(599));
+    assert!(needs_validation(600));
+}
+
+#[test]
+fn test_expires_is_relative_to_date_in_the_future() {
+    let needs_validation = |elapsed| {
+        store_and_lookup(
+            StatusCode::OK,
+            header_map(&[
+                (DATE, "Thu, 01 Jan 2099 00:00:00 GMT"),
+                (EXPIRES, "Thu, 01 Jan 2099 00:01:00 GMT"),
+            ]),
+            HeaderMap::new(),
+            elapsed,
+            |_| {},
+        )
+    };
+    assert!(!needs_validation(59));
+    assert!(needs_validation(60));
+}
+
+#[test]
+fn test_expires_without_valid_date_is_relative_to_now() {
+    let expires = "Thu, 01 Jan 2099 00:01:00 GMT";
+    let needs_validation = |date| {
+        let mut headers = header_map(&[(EXPIRES, expires)]);
+        if let Some(date) = date {
+            headers.insert(DATE, HeaderValue::from_static(date));
+        }
+        store_and_lookup(StatusCode::OK, headers, HeaderMap::new(), 3600, |_| {})
+    };
+    assert!(!needs_validation(None));
+    assert!(!needs_validation(Some("yesterday")));
+}
+
+#[test]
+fn test_compressed_bodies_take_less_memory_and_round_trip() {
+    let url = ServoUrl::parse("https://servo.org/compressible.txt").unwrap();
+    let body: Vec<u8> = b"servo ".iter().cycle().take(64 * 1024).cloned().collect();
+    let mut cache = HttpCache::default();
+    cache.set_compress_bodies(true);
+    cache.set_memory_budget(Some(16 * 1024));
+    store_body(&mut cache, &url, body.clone());
+
+    let entry = cache.iter_entries().next().unwrap();
+    assert!(entry.body_size < body.len());
+    let request = create_request(&url, HeaderMap::new());
+    let cached_response = cache.construct_response(&request, &mut None).unwrap();
+    assert_eq!(
+        *cached_response.response.body.lock().unwrap(),
+        ResponseBody::Done(body.clone())
+    );
+    let partial_response = range_response(&cache, &url, "bytes=6-10").unwrap();
+    assert_eq!(
+        *partial_response.body.lock().unwrap(),
+        ResponseBody::Done(b"servo".to_vec())
+    );
+}
+
+#[test]
+fn test_small_bodies_are_not_compressed() {
+    let url = ServoUrl::parse("https://servo.org/small.txt").unwrap();
+    let mut cache = HttpCache::default();
+    cache.set_compress_bodies(true);
+    store_body(&mut cache, &url, b"body".to_vec());
+
+    assert_eq!(cache.iter_entries().next().unwrap().body_size, 4);
+    assert!(is_cached(&cache, &url));
+}
+
+fn store_last_modified_resource(
+    cache: &mut HttpCache,
+    url: &ServoUrl,
+    last_modified: &'static str,
+) {
+    let request = create_request(url, HeaderMap::new());
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=0"));
+    headers.insert(LAST_MODIFIED, HeaderValue::from_static(last_modified));
+    let response = create_response(url, headers, ResponseBody::Done(b"body".to_vec()));
+    cache.store(&request, &response);
+}
+
+fn not_modified_since(url: &ServoUrl, last_modified: &'static str) -> Response {
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+    headers.insert(LAST_MODIFIED, HeaderValue::from_static(last_modified));
+    let mut not_modified = create_response(url, headers, ResponseBody::Empty);
+    not_modified.status = StatusCode::NOT_MODIFIED.into();
+    not_modified
+}
+
+#[test]
+fn test_last_modified_only_resource_is_revalidated_with_if_modified_since() {
+    let url = ServoUrl::parse("https://servo.org/last-modified").unwrap();
+    let request = create_request(&url, HeaderMap::new());
+    let mut cache = HttpCache::default();
+    // An obsolete date format is sent back as received.
+    store_last_modified_resource(&mut cache, &url, "Wednesday, 21-Oct-15 07:28:00 GMT");
+
+    let conditional_headers = cache.conditional_headers_for(&request).unwrap();
+    assert!(conditional_headers.get(IF_NONE_MATCH).is_none());
+    assert_eq!(
+        conditional_headers.get(IF_MODIFIED_SINCE).unwrap(),
+        "Wednesday, 21-Oct-15 07:28:00 GMT"
+    );
+
+    let not_modified = not_modified_since(&url, "Wed, 21 Oct 2015 07:28:00 GMT");
+    let refreshed = cache.refresh(&request, not_modified, &mut None).unwrap();
+    assert_eq!(
+        *refreshed.body.lock().unwrap(),
+        ResponseBody::Done(b"body".to_vec())
+    );
+    let cached_response = cache.construct_response(&request, &mut None).unwrap();
+    assert!(!cached_response.needs_validation);
+}
+
+#[test]
+fn test_not_modified_with_other_last_modified_refreshes_selected_resource() {
+    let url = ServoUrl::parse("https://servo.org/modified-since").unwrap();
+    let request = create_request(&url, HeaderMap::new());
+    let mut cache = HttpCache::default();
+    store_last_modified_resource(&mut cache, &url, "Wed, 21 Oct 2015 07:28:00 GMT");
+
+    // The 304 response doesn't select the stored resource, which the request still selects.
+    let not_modified = not_modified_since(&url, "Thu, 22 Oct 2015 07:28:00 GMT");
+    let refreshed = cache.refresh(&request, not_modified, &mut None).unwrap();
+    assert_eq!(
+        *refreshed.body.lock().unwrap(),
+        ResponseBody::Done(b"body".to_vec())
+    );
+    let cached_response = cache.construct_response(&request, &mut None).unwrap();
+    assert!(!cached_response.needs_validation);
+}
+
+fn refreshed_body(
+    stored: (HeaderName, &'static str),
+    not_modified: (HeaderName, &'static str),
+) -> Option<ResponseBody> {
+    let url = ServoUrl::parse("https://servo.org/refreshed").unwrap();
+    let request = create_request(&url, HeaderMap::new());
+    let response = create_response(
+        &url,
+        header_map(&[(CACHE_CONTROL, "max-age=0"), stored]),
+        ResponseBody::Done(b"body".to_vec()),
+    );
+    let mut cache = HttpCache::default();
+    cache.store(&request, &response);
+
+    let mut not_modified = create_response(&url, header_map(&[not_modified]), ResponseBody::Empty);
+    not_modified.status = StatusCode::NOT_MODIFIED.into();
+    cache
+        .refresh(&request, not_modified, &mut None)
+        .map(|refreshed| refreshed.body.lock().unwrap().clone())
+}
+
+#[test]
+fn test_not_modified_with_weak_entity_tag_refreshes_strong_one() {
+    assert_eq!(
+        refreshed_body((ETAG, "\"v1\""), (ETAG, "W/\"v1\"")),
+        Some(ResponseBody::Done(b"body".to_vec()))
+    );
+}
+
+#[test]
+fn test_not_modified_with_entity_tag_refreshes_last_modified_only_resource() {
+    assert_eq!(
+        refreshed_body(
+            (LAST_MODIFIED, "Wed, 21 Oct 2015 07:28:00 GMT"),
+            (ETAG, "\"v1\"")
+        ),
+        Some(ResponseBody::Done(b"body".to_vec()))
+    );
+}
+
+#[test]
+fn test_store_filter_vetoes_storage() {
+    let cookie_url = ServoUrl::parse("https://servo.org/with-cookie").unwrap();
+    let plain_url = ServoUrl::parse("https://servo.org/without-cookie").unwrap();
+    let mut cache = HttpCache::default();
+    cache.set_store_filter(Some(Box::new(|_, response| {
+        !response.headers.contains_key(SET_COOKIE)
+    })));
+    for url in [&cookie_url, &plain_url] {
+        let request = create_request(url, HeaderMap::new());
+        let mut headers = HeaderMap::new();
+        headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+        if url == &cookie_url {
+            headers.insert(SET_COOKIE, HeaderValue::from_static("session=1"));
+        }
+        let response = create_response(url, headers, ResponseBody::Done(b"body".to_vec()));
+        cache.store(&request, &response);
+    }
+
+    assert!(!is_cached(&cache, &cookie_url));
+    assert!(is_cached(&cache, &plain_url));
+}
+
+#[test]
+fn test_body_bytes_excludes_overhead() {
+    let mut cache = HttpCache::default();
+    for (path, body_len) in [("first", 100), ("second", 250)] {
+        let url = ServoUrl::parse(&format!("https://servo.org/{}", path)).unwrap();
+        let request = create_request(&url, HeaderMap::new());
+        let mut headers = HeaderMap::new();
+        headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+        let response = create_response(&url, headers, ResponseBody::Done(vec![0; body_len]));
+        cache.store(&request, &response);
+    }
+
+    assert_eq!(cache.body_bytes(), 350);
+    let mut ops = MallocSizeOfOps::new(servo_allocator::usable_size, None, None);
+    assert!(cache.size_of(&mut ops) > cache.body_bytes());
+}
+
+fn accept_language_variant_matches(
+    stored: Option<&'static str>,
+    current: Option<&'static str>,
+) -> bool {
+    let url = ServoUrl::parse("https://servo.org/localized").unwrap();
+    let request_headers = |accept_language: Option<&'static str>| {
+        let mut headers = HeaderMap::new();
+        if let Some(accept_language) = accept_language {
+            headers.insert(ACCEPT_LANGUAGE, HeaderValue::from_static(accept_language));
+        }
+        headers
+    };
+    let request = create_request(&url, request_headers(stored));
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+    headers.insert(VARY, HeaderValue::from_static("Accept-Language"));
+    let response = create_response(&url, headers, ResponseBody::Done(b"body".to_vec()));
+    let mut cache = HttpCache::default();
+    cache.store(&request, &response);
+
+    let request = create_request(&url, request_headers(current));
+    cache.construct_response(&request, &mut None).is_some()
+}
+
+#[test]
+fn test_vary_field_present_in_both_requests() {
+    assert!(accept_language_variant_matches(Some("fr"), Some("fr")));
+    assert!(!accept_language_variant_matches(Some("fr"), Some("en")));
+}
+
+#[test]
+fn test_vary_field_absent_from_both_requests() {
+    assert!(accept_language_variant_matches(None, None));
+}
+
+#[test]
+fn test_vary_field_only_in_original_request() {
+    assert!(!accept_language_variant_matches(Some("fr"), None));
+}
+
+#[test]
+fn test_vary_field_only_in_current_request() {
+    assert!(!accept_language_variant_matches(None, Some("fr")));
+}
+
+fn lookup_with_if_none_match(if_none_match: &'static str) -> CachedResponse {
+    let url = ServoUrl::parse("https://servo.org/conditional").unwrap();
+    let request = create_request(&url, HeaderMap::new());
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+    headers.insert(ETAG, HeaderValue::from_static("\"v1\""));
+    let response = create_response(&url, headers, ResponseBody::Done(b"body".to_vec()));
+    let mut cache = HttpCache::default();
+    cache.store(&request, &response);
+
+    let mut headers = HeaderMap::new();
+    headers.insert(IF_NONE_MATCH, HeaderValue::from_static(if_none_match));
+    let request = create_request(&url, headers);
+    cache
+        .construct_response(&request, &mut None)
+        .expect("a fresh resource should be served")
+}
+
+#[test]
+fn test_fresh_resource_matching_if_none_match_is_not_modified() {
+    let cached_response = lookup_with_if_none_match("\"v1\"");
+    assert!(!cached_response.needs_validation);
+    assert_eq!(cached_response.response.status, StatusCode::NOT_MODIFIED);
+    assert_eq!(
+        *cached_response.response.body.lock().unwrap(),
+        ResponseBody::Empty
+    );
+}
+
+#[test]
+fn test_fresh_resource_not_matching_if_none_match_is_served() {
+    let cached_response = lookup_with_if_none_match("\"v0\"");
+    assert!(!cached_response.needs_validation);
+    assert_eq!(cached_response.response.status, StatusCode::OK);
+    assert_eq!(
+        *cached_response.response.body.lock().unwrap(),
+        ResponseBody::Done(b"body".to_vec())
+    );
+}
+
+#[test]
+fn test_received_resource_matching_if_none_match_is_not_modified() {
+    let url = ServoUrl::parse("https://servo.org/conditional-stream").unwrap();
+    let request = create_request(&url, HeaderMap::new());
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+    headers.insert(ETAG, HeaderValue::from_static("\"v1\""));
+    let response = create_response(&url, headers, ResponseBody::Receiving(vec![]));
+    let mut cache = HttpCache::default();
+    cache.store(&request, &response);
+
+    let mut headers = HeaderMap::new();
+    headers.insert(IF_NONE_MATCH, HeaderValue::from_static("\"v1\""));
+    let mut conditional_request = create_request(&url, headers);
+    conditional_request.cache_mode = CacheMode::ForceCache;
+    let mut done_chan = Some(unbounded());
+    let cached_response = cache
+        .construct_response(&conditional_request, &mut done_chan)
+        .unwrap();
+    assert_eq!(cached_response.response.status, StatusCode::NOT_MODIFIED);
+    assert!(done_chan.is_none());
+
+    // The consumer still waiting on the body is the only one woken-up.
+    let mut done_chan = Some(unbounded());
+    assert!(cache.construct_response(&request, &mut done_chan).is_some());
+    let (_, mut done_receiver) = done_chan.unwrap();
+    *response.body.lock().unwrap() = ResponseBody::Done(b"body".to_vec());
+    cache.update_awaiting_consumers(&request, &response);
+    assert!(matches!(done_receiver.try_recv(), Ok(Data::Done)));
+}
+
+#[test]
+fn test_finalized_body_serves_range_requests() {
+    let url = ServoUrl::parse("https://servo.org/finalized.bin").unwrap();
+    let request = create_request(&url, HeaderMap::new());
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+    let response = create_response(&url, headers, ResponseBody::Receiving(vec![]));
+    let mut cache = HttpCache::default();
+    let mut recv = await_stored_body(&mut cache, &request, &response);
+
+    cache.finalize_body(&request, b"0123456789".to_vec());
+
+    assert!(matches!(recv.try_recv(), Ok(Data::Done)));
+    let partial_response = range_response(&cache, &url, "bytes=2-5").unwrap();
+    assert_eq!(partial_response.status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(
+        *partial_response.body.lock().unwrap(),
+        ResponseBody::Done(b"2345".to_vec())
+    );
+}
+
+fn duplicated_vary_variant_matches(stored: &'static str, current: &'static str) -> bool {
+    let url = ServoUrl::parse("https://servo.org/duplicated-vary").unwrap();
+    let mut headers = HeaderMap::new();
+    headers.insert(ACCEPT_LANGUAGE, HeaderValue::from_static(stored));
+    let request = create_request(&url, headers);
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+    headers.append(
+        VARY,
+        HeaderValue::from_static("Accept-Language, accept-language"),
+    );
+    headers.append(VARY, HeaderValue::from_static("ACCEPT-LANGUAGE"));
+    let response = create_response(&url, headers, ResponseBody::Done(b"body".to_vec()));
+    let mut cache = HttpCache::default();
+    cache.store(&request, &response);
+
+    let mut headers = HeaderMap::new();
+    headers.insert(ACCEPT_LANGUAGE, HeaderValue::from_static(current));
+    let request = create_request(&url, headers);
+    match cache.explain(&request) {
+        CacheLookupExplanation::VaryMismatch(name) => {
+            assert_eq!(name, "accept-language");
+            false
+        },
+        _ => true,
+    }
+}
+
+#[test]
+fn test_duplicated_vary_field_names_are_compared_once() {
+    assert!(duplicated_vary_variant_matches("fr", "fr"));
+    assert!(!duplicated_vary_variant_matches("fr", "en"));
+}
+
+fn heuristic_freshness(cache: &mut HttpCache, status: StatusCode) -> Duration {
+    let url = ServoUrl::parse(&format!("https://servo.org/heuristic/{}", status.as_u16())).unwrap();
+    let request = create_request(&url, HeaderMap::new());
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        LAST_MODIFIED,
+        HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"),
+    );
+    let mut response = create_response(&url, headers, ResponseBody::Done(vec![]));
+    response.status = status.into();
+    cache.store(&request, &response);
+    cache
+        .freshness_remaining(&request)
+        .expect("the response should be stored")
+}
+
+#[test]
+fn test_heuristic_cap_per_status_code() {
+    let day = Duration::from_secs(24 * 60 * 60);
+    let mut cache = HttpCache::default();
+    cache.set_heuristic_caps([(StatusCode::MOVED_PERMANENTLY, 7 * day)].into());
+
+    let redirect_freshness = heuristic_freshness(&mut cache, StatusCode::MOVED_PERMANENTLY);
+    let ok_freshness = heuristic_freshness(&mut cache, StatusCode::OK);
+    assert!(redirect_freshness > 6 * day);
+    assert!(redirect_freshness <= 7 * day);
+    assert!(ok_freshness <= day);
+}
+
+#[test]
+fn test_enormous_range_bounds_are_not_truncated() {
+    let url = ServoUrl::parse("https://servo.org/enormous.bin").unwrap();
+    let mut cache = HttpCache::default();
+    store_body(&mut cache, &url, b"0123456789".to_vec());
+
+    // Truncating these bounds to 32 bits would select the first bytes of the body.
+    for range in ["bytes=4294967296-4294967299", "bytes=4294967296-"] {
+        let response = range_response(&cache, &url, range).unwrap();
+        assert_eq!(response.status, StatusCode::RANGE_NOT_SATISFIABLE);
+    }
+    assert!(range_response(&cache, &url, "bytes=0-18446744073709551615").is_none());
+}
+
+#[test]
+fn test_range_within_partial_at_enormous_offset() {
+    let url = ServoUrl::parse("https://servo.org/enormous-partial.bin").unwrap();
+    let mut cache = HttpCache::default();
+    store_partial(
+        &mut cache,
+        &url,
+        "bytes 4294967296-4294967305/4294967306",
+        b"0123456789",
+    );
+
+    let response = range_response(&cache, &url, "bytes=4294967298-4294967301").unwrap();
+    assert_eq!(
+        *response.body.lock().unwrap(),
+        ResponseBody::Done(b"2345".to_vec())
+    );
+    let response = range_response(&cache, &url, "bytes=4294967304-").unwrap();
+    assert_eq!(
+        *response.body.lock().unwrap(),
+        ResponseBody::Done(b"89".to_vec())
+    );
+    assert!(range_response(&cache, &url, "bytes=4294967300-18446744073709551615").is_none());
+}
+
+#[test]
+fn test_ranges_from_a_single_resource_are_not_partial_merges() {
+    let complete_url = ServoUrl::parse("https://servo.org/complete.bin").unwrap();
+    let partial_url = ServoUrl::parse("https://servo.org/partial.bin").unwrap();
+    let mut cache = HttpCache::default();
+    store_body(&mut cache, &complete_url, b"0123456789".to_vec());
+    store_partial(&mut cache, &partial_url, "bytes 0-4/10", b"01234");
+    store_partial(&mut cache, &partial_url, "bytes 5-9/10", b"56789");
+
+    for (url, range) in [(&complete_url, "bytes=2-5"), (&partial_url, "bytes=1-3")] {
+        let mut headers = HeaderMap::new();
+        headers.insert(RANGE, HeaderValue::from_static(range));
+        let request = create_request(url, headers);
+        let cached_response = cache.construct_response(&request, &mut None).unwrap();
+        assert!(!cached_response.from_partial_merge);
+    }
+}
+
+fn is_invalidated_by_post(status: StatusCode) -> bool {
+    let url = ServoUrl::parse("https://servo.org/form").unwrap();
+    let request = create_request(&url, HeaderMap::new());
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+    let response = create_response(&url, headers, ResponseBody::Done(b"form".to_vec()));
+    let mut cache = HttpCache::default();
+    cache.store(&request, &response);
+
+    let mut post_request = create_request(&url, HeaderMap::new());
+    post_request.method = Method::POST;
+    let mut post_response = create_response(&url, HeaderMap::new(), ResponseBody::Empty);
+    post_response.status = status.into();
+    cache.invalidate(&post_request, &post_response);
+
+    cache
+        .construct_response(&request, &mut None)
+        .unwrap()
+        .needs_validation
+}
+
+#[test]
+fn test_successful_post_invalidates_its_url() {
+    assert!(is_invalidated_by_post(StatusCode::OK));
+    assert!(is_invalidated_by_post(StatusCode::SEE_OTHER));
+}
+
+#[test]
+fn test_failed_post_does_not_invalidate_its_url() {
+    assert!(!is_invalidated_by_post(StatusCode::INTERNAL_SERVER_ERROR));
+    assert!(!is_invalidated_by_post(StatusCode::NOT_FOUND));
+}
+
+#[test]
+fn test_opaque_responses_are_not_stored() {
+    let opaque_url = ServoUrl::parse("https://example.com/opaque.js").unwrap();
+    let redirect_url = ServoUrl::parse("https://example.com/opaque-redirect").unwrap();
+    let basic_url = ServoUrl::parse("https://example.com/basic.js").unwrap();
+    let mut cache = HttpCache::default();
+    for (url, filter_type) in [
+        (&opaque_url, ResponseType::Opaque),
+        (&redirect_url, ResponseType::OpaqueRedirect),
+        (&basic_url, ResponseType::Basic),
+    ] {
+        let request = create_request(url, HeaderMap::new());
+        let mut headers = HeaderMap::new();
+        headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+        let response = create_response(url, headers, ResponseBody::Done(b"body".to_vec()))
+            .to_filtered(filter_type);
+        cache.store(&request, &response);
+    }
+
+    assert!(!is_cached(&cache, &opaque_url));
+    assert!(!is_cached(&cache, &redirect_url));
+    assert!(is_cached(&cache, &basic_url));
+}
+
+#[test]
+fn test_store_many_only_stores_cacheable_responses() {
+    let mut cache = HttpCache::default();
+    let items: Vec<(Request, Response)> = [
+        ("cacheable", "max-age=3600"),
+        ("no-store", "no-store"),
+        ("also-cacheable", "public, max-age=60"),
+    ]
+    .into_iter()
+    .map(|(path, cache_control)| {
+        let url = ServoUrl::parse(&format!("https://servo.org/prefetch/{}", path)).unwrap();
+        let request = create_request(&url, HeaderMap::new());
+        let mut headers = HeaderMap::new();
+        headers.insert(CACHE_CONTROL, HeaderValue::from_static(cache_control));
+        let response = create_response(&url, headers, ResponseBody::Done(b"body".to_vec()));
+        (request, response)
+    })
+    .collect();
+
+    cache.store_many(items.iter().map(|(request, response)| (request, response)));
+
+    let cached: Vec<bool> = items
+        .iter()
+        .map(|(request, _)| is_cached(&cache, &request.url()))
+        .collect();
+    assert_eq!(cached, [true, false, true]);
+}
+
+fn private_response_is_stored_in_mode(mode: HttpCacheMode) -> bool {
+    store_and_construct(
+        HeaderMap::new(),
+        header_map(&[(CACHE_CONTROL, "private, max-age=3600")]),
+        |cache, _| cache.set_mode(mode),
+    )
+    .is_some()
+}
+
+#[test]
+fn test_private_response_is_only_stored_in_private_mode() {
+    assert!(private_response_is_stored_in_mode(HttpCacheMode::Private));
+    assert!(!private_response_is_stored_in_mode(HttpCacheMode::Shared));
+}
+
+#[test]
+fn test_s_maxage_only_applies_in_shared_mode() {
+    // Not cacheable by default, so that no heuristic freshness applies.
+    let needs_validation = |mode| {
+        store_and_lookup(
+            StatusCode::FOUND,
+            header_map(&[(CACHE_CONTROL, "s-maxage=3600")]),
+            HeaderMap::new(),
+            0,
+            |cache| cache.set_mode(mode),
+        )
+    };
+    assert!(!needs_validation(HttpCacheMode::Shared));
+    assert!(needs_validation(HttpCacheMode::Private));
+}
+
+#[test]
+fn test_s_maxage_overrides_max_age_in_shared_mode() {
+    // Not cacheable by default, so that no heuristic freshness applies.
+    let needs_validation = |mode, cache_control| {
+        store_and_lookup(
+            StatusCode::FOUND,
+            header_map(&[(CACHE_CONTROL, cache_control)]),
+            HeaderMap::new(),
+            0,
+            |cache| cache.set_mode(mode),
+        )
+    };
+    assert!(!needs_validation(
+        HttpCacheMode::Shared,
+        "max-age=0, s-maxage=3600"
+    ));
+    assert!(needs_validation(
+        HttpCacheMode::Shared,
+        "max-age=3600, s-maxage=0"
+    ));
+    assert!(!needs_validation(
+        HttpCacheMode::Private,
+        "max-age=3600, s-maxage=0"
+    ));
+}
+
+#[test]
+fn test_get_by_url_ignores_vary() {
+    let url = ServoUrl::parse("https://servo.org/varying").unwrap();
+    let mut request_headers = HeaderMap::new();
+    request_headers.insert(ACCEPT_LANGUAGE, HeaderValue::from_static("fr"));
+    let request = create_request(&url, request_headers);
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("text/html"));
+    headers.insert(VARY, HeaderValue::from_static("Accept-Language"));
+    let response = create_response(&url, headers, ResponseBody::Done(b"bonjour".to_vec()));
+    let mut cache = HttpCache::default();
+    cache.store(&request, &response);
+
+    assert!(!is_cached(&cache, &url));
+    let info = cache.get_by_url(&url).unwrap();
+    assert_eq!(info.url, url);
+    assert_eq!(info.content_type.as_deref(), Some("text/html"));
+    assert_eq!(info.body_size, 7);
+    assert!(
+        cache
+            .get_by_url(&ServoUrl::parse("https://servo.org/missing").unwrap())
+            .is_none()
+    );
+}
+
+#[test]
+fn test_concurrent_lookups_refreshes_and_stores_do_not_deadlock() {
+    const ITERATIONS: usize = 200;
+    let url = ServoUrl::parse("https://servo.org/contended.bin").unwrap();
+    let cache = Arc::new(RwLock::new(HttpCache::default()));
+    let (finished_sender, finished_receiver) = mpsc::channel();
+    let stored_headers = || {
+        let mut headers = HeaderMap::new();
+        headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=0"));
+        headers.insert(ETAG, HeaderValue::from_static("\"contended\""));
+        headers
+    };
+
+    let workers: Vec<Box<dyn Fn() + Send>> = vec![
+        // Store resources whose body is received afterwards, as the network would.
+        Box::new({
+            let (cache, url) = (cache.clone(), url.clone());
+            move || {
+                let request = create_request(&url, HeaderMap::new());
+                let response =
+                    create_response(&url, stored_headers(), ResponseBody::Receiving(vec![]));
+                cache.write().unwrap().store(&request, &response);
+                *response.body.lock().unwrap() = ResponseBody::Done(b"0123456789".to_vec());
+                cache
+                    .write()
+                    .unwrap()
+                    .update_awaiting_consumers(&request, &response);
+            }
+        }),
+        // Construct full and partial responses from the stored resources.
+        Box::new({
+            let (cache, url) = (cache.clone(), url.clone());
+            move || {
+                for range in [None, Some("bytes=2-5"), Some("bytes=4-")] {
+                    let mut headers = HeaderMap::new();
+                    if let Some(range) = range {
+                        headers.insert(RANGE, HeaderValue::from_static(range));
+                    }
+                    let request = create_request(&url, headers);
+                    let (send, recv) = unbounded();
+                    let mut done_chan = Some((send, recv));
+                    cache
+                        .read()
+                        .unwrap()
+                        .construct_response(&request, &mut done_chan);
+                }
+            }
+        }),
+        // Refresh the stored resources upon their validation.
+        Box::new({
+            let (cache, url) = (cache.clone(), url.clone());
+            move || {
+                let request = create_request(&url, HeaderMap::new());
+                let mut not_modified = create_response(&url, stored_headers(), ResponseBody::Empty);
+                not_modified.status = StatusCode::NOT_MODIFIED.into();
+                let (send, recv) = unbounded();
+                let mut done_chan = Some((send, recv));
+                cache
+                    .write()
+                    .unwrap()
+                    .refresh(&request, not_modified, &mut done_chan);
+            }
+        }),
+    ];
+    let worker_count = workers.len();
+    for worker in workers {
+        let finished_sender = finished_sender.clone();
+        thread::spawn(move || {
+            for _ in 0..ITERATIONS {
+                worker();
+            }
+            let _ = finished_sender.send(());
+        });
+    }
+
+    for _ in 0..worker_count {
+        finished_receiver
+            .recv_timeout(Duration::from_secs(30))
+            .expect("concurrent users of the cache shouldn't deadlock");
+    }
+}
+
+fn language_and_encoding_headers(
+    language: Option<&'static str>,
+    encoding: &'static str,
+) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    if let Some(language) = language {
+        headers.insert(ACCEPT_LANGUAGE, HeaderValue::from_static(language));
+    }
+    headers.insert(ACCEPT_ENCODING, HeaderValue::from_static(encoding));
+    headers
+}
+
+#[test]
+fn test_variant_selection_by_secondary_key() {
+    let url = ServoUrl::parse("https://servo.org/selected").unwrap();
+    let mut cache = HttpCache::default();
+    let variants = [
+        (Some("fr"), "gzip, br", "fr-compressed"),
+        (Some("fr"), "identity", "fr-identity"),
+        (Some("en"), "gzip, br", "en-compressed"),
+        (None, "gzip, br", "default-compressed"),
+    ];
+    for (language, encoding, body) in variants {
+        let request = create_request(&url, language_and_encoding_headers(language, encoding));
+        let mut headers = HeaderMap::new();
+        headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+        headers.insert(
+            VARY,
+            HeaderValue::from_static("Accept-Language, Accept-Encoding"),
+        );
+        let response = create_response(&url, headers, ResponseBody::Done(body.into()));
+        cache.store(&request, &response);
+    }
+
+    let lookups = [
+        (Some("fr"), "gzip, br", Some("fr-compressed")),
+        (Some("fr"), "br;q=0.9, GZIP", Some("fr-compressed")),
+        (Some("fr"), "identity", Some("fr-identity")),
+        (Some("en"), "br, gzip", Some("en-compressed")),
+        (Some("en"), "identity", None),
+        (None, "gzip, br", Some("default-compressed")),
+        (Some("de"), "gzip, br", None),
+        (Some("fr"), "gzip", None),
+    ];
+    for (language, encoding, expected_body) in lookups {
+        let request = create_request(&url, language_and_encoding_headers(language, encoding));
+        let served_body = cache
+            .construct_response(&request, &mut None)
+            .map(|cached_response| cached_response.response.body.lock().unwrap().clone());
+        assert_eq!(
+            served_body,
+            expected_body.map(|body| ResponseBody::Done(body.into())),
+            "{:?} {:?}",
+            language,
+            encoding
+        );
+    }
+}
+
+#[test]
+fn test_secondary_key_follows_refreshed_vary_header() {
+    let url = ServoUrl::parse("https://servo.org/flavored").unwrap();
+    let flavored_request = |flavor: &'static str| {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT_LANGUAGE, HeaderValue::from_static("fr"));
+        headers.insert("x-flavor", HeaderValue::from_static(flavor));
+        create_request(&url, headers)
+    };
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+    headers.insert(ETAG, HeaderValue::from_static("\"flavored\""));
+    headers.insert(VARY, HeaderValue::from_static("Accept-Language"));
+    let response = create_response(&url, headers, ResponseBody::Done(b"body".to_vec()));
+    let mut cache = HttpCache::default();
+    cache.store(&flavored_request("vanilla"), &response);
+    assert!(
+        cache
+            .construct_response(&flavored_request("chocolate"), &mut None)
+            .is_some()
+    );
+
+    let mut headers = HeaderMap::new();
+    headers.insert(ETAG, HeaderValue::from_static("\"flavored\""));
+    headers.insert(VARY, HeaderValue::from_static("Accept-Language, X-Flavor"));
+    let mut not_modified = create_response(&url, headers, ResponseBody::Empty);
+    not_modified.status = StatusCode::NOT_MODIFIED.into();
+    assert!(
+        cache
+            .refresh(&flavored_request("vanilla"), not_modified, &mut None)
+            .is_some()
+    );
+
+    assert!(
+        cache
+            .construct_response(&flavored_request("vanilla"), &mut None)
+            .is_some()
+    );
+    assert!(
+        cache
+            .construct_response(&flavored_request("chocolate"), &mut None)
+            .is_none()
+    );
+}
+
+#[test]
+fn test_verify_integrity_flags_altered_bodies() {
+    let altered_url = ServoUrl::parse("https://servo.org/altered").unwrap();
+    let intact_url = ServoUrl::parse("https://servo.org/intact").unwrap();
+    let mut cache = HttpCache::default();
+    cache.set_hash_bodies(true);
+    let mut responses = vec![];
+    for url in [&altered_url, &intact_url] {
+        let request = create_request(url, HeaderMap::new());
+        let mut headers = HeaderMap::new();
+        headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+        let response = create_response(url, headers, ResponseBody::Done(b"body".to_vec()));
+        cache.store(&request, &response);
+        responses.push(response);
+    }
+    assert!(cache.verify_integrity().is_empty());
+
+    // The stored body is shared with the response.
+    *responses[0].body.lock().unwrap() = ResponseBody::Done(b"b0dy".to_vec());
+
+    assert_eq!(cache.verify_integrity(), vec![altered_url]);
+}
+
+fn store_received_body(cache: &mut HttpCache, url: &ServoUrl, body: Vec<u8>) -> Response {
+    let request = create_request(url, HeaderMap::new());
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+    let response = create_response(url, headers, ResponseBody::Receiving(vec![]));
+    cache.store(&request, &response);
+    *response.body.lock().unwrap() = ResponseBody::Done(body);
+    cache.update_awaiting_consumers(&request, &response);
+    response
+}
+
+#[test]
+fn test_received_bodies_are_compressed_on_completion() {
+    let url = ServoUrl::parse("https://servo.org/streamed.txt").unwrap();
+    let body: Vec<u8> = b"servo ".iter().cycle().take(64 * 1024).cloned().collect();
+    let mut cache = HttpCache::default();
+    cache.set_compress_bodies(true);
+    store_received_body(&mut cache, &url, body.clone());
+
+    let entry = cache.iter_entries().next().unwrap();
+    assert!(entry.body_size < body.len());
+    assert_eq!(cache.body_bytes_for(&url), Some(body));
+}
+
+#[test]
+fn test_received_bodies_are_hashed_on_completion() {
+    let url = ServoUrl::parse("https://servo.org/streamed.txt").unwrap();
+    let mut cache = HttpCache::default();
+    cache.set_hash_bodies(true);
+    let response = store_received_body(&mut cache, &url, b"body".to_vec());
+    assert!(cache.verify_integrity().is_empty());
+
+    // The stored body is shared with the response.
+    *response.body.lock().unwrap() = ResponseBody::Done(b"b0dy".to_vec());
+    assert_eq!(cache.verify_integrity(), vec![url]);
+}
+
+#[test]
+fn test_range_within_partial_of_unknown_length() {
+    let url = ServoUrl::parse("https://servo.org/streaming.bin").unwrap();
+    let mut cache = HttpCache::default();
+    store_partial(&mut cache, &url, "bytes 0-9/*", b"0123456789");
+
+    let response = range_response(&cache, &url, "bytes=2-5").unwrap();
+    assert_eq!(response.status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(
+        *response.body.lock().unwrap(),
+        ResponseBody::Done(b"2345".to_vec())
+    );
+    // Bytes past those received, and suffixes, can't be known to be satisfied.
+    assert!(range_response(&cache, &url, "bytes=5-12").is_none());
+    assert!(range_response(&cache, &url, "bytes=5-").is_none());
+    assert!(range_response(&cache, &url, "bytes=-2").is_none());
+}
+
+#[test]
+fn test_max_keys_evicts_least_recently_accessed_key() {
+    let urls: Vec<ServoUrl> = ["first", "second", "third"]
+        .iter()
+        .map(|path| ServoUrl::parse(&format!("https://servo.org/keys/{}", path)).unwrap())
+        .collect();
+    let mut cache = HttpCache::default();
+    cache.set_max_keys(Some(2));
+    store_body(&mut cache, &urls[0], b"first".to_vec());
+    store_body(&mut cache, &urls[1], b"second".to_vec());
+    // Accessing the first key makes the second one the least recently accessed.
+    assert!(is_cached(&cache, &urls[0]));
+    store_body(&mut cache, &urls[2], b"third".to_vec());
+
+    assert!(is_cached(&cache, &urls[0]));
+    assert!(!is_cached(&cache, &urls[1]));
+    assert!(is_cached(&cache, &urls[2]));
+}
+
+#[test]
+fn test_served_responses_carry_via_header() {
+    let url = ServoUrl::parse("https://servo.org/via.bin").unwrap();
+    let mut cache = HttpCache::default();
+    store_body(&mut cache, &url, b"0123456789".to_vec());
+    let request = create_request(&url, HeaderMap::new());
+    let response = cache
+        .construct_response(&request, &mut None)
+        .unwrap()
+        .response;
+    assert_eq!(response.headers.get(VIA).unwrap(), "1.1 servo-cache");
+
+    cache.set_via_pseudonym("embedder-cache".into());
+    let response = range_response(&cache, &url, "bytes=2-5").unwrap();
+    let via: Vec<_> = response.headers.get_all(VIA).iter().collect();
+    assert_eq!(via, ["1.1 embedder-cache"]);
+}
+
+#[test]
+fn test_via_header_is_not_duplicated() {
+    let url = ServoUrl::parse("https://servo.org/proxied").unwrap();
+    let request = create_request(&url, HeaderMap::new());
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+    headers.insert(VIA, HeaderValue::from_static("1.0 proxy, 1.1 servo-cache"));
+    let response = create_response(&url, headers, ResponseBody::Done(b"body".to_vec()));
+    let mut cache = HttpCache::default();
+    cache.store(&request, &response);
+
+    let response = cache
+        .construct_response(&request, &mut None)
+        .unwrap()
+        .response;
+    let via: Vec<_> = response.headers.get_all(VIA).iter().collect();
+    assert_eq!(via, ["1.0 proxy, 1.1 servo-cache"]);
+}
+
+#[test]
+fn test_bare_max_stale_accepts_any_staleness() {
+    let one_year = 365 * 24 * 60 * 60;
+    let needs_validation = |request_directives| {
+        store_and_lookup(
+            StatusCode::OK,
+            header_map(&[(CACHE_CONTROL, "max-age=60")]),
+            header_map(&[(CACHE_CONTROL, request_directives)]),
+            one_year,
+            |_| {},
+        )
+    };
+    assert!(!needs_validation("max-stale"));
+    assert!(needs_validation("max-stale=60"));
+}
+
+#[test]
+fn test_must_revalidate_overrides_bare_max_stale() {
+    let needs_validation = |elapsed| {
+        store_and_lookup(
+            StatusCode::OK,
+            header_map(&[(CACHE_CONTROL, "max-age=60, must-revalidate")]),
+            header_map(&[(CACHE_CONTROL, "max-stale")]),
+            elapsed,
+            |_| {},
+        )
+    };
+    assert!(!needs_validation(30));
+    assert!(needs_validation(120));
+}
+
+#[test]
+fn test_har_entries_describe_stored_resources() {
+    let html_url = ServoUrl::parse("https://servo.org/index.html").unwrap();
+    let missing_url = ServoUrl::parse("https://servo.org/missing.html").unwrap();
+    let mut cache = HttpCache::default();
+    store_with_content_type(&mut cache, &html_url, "text/html");
+    let request = create_request(&missing_url, HeaderMap::new());
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+    let mut response = create_response(&missing_url, headers, ResponseBody::Done(vec![0; 12]));
+    response.status = StatusCode::NOT_FOUND.into();
+    cache.store(&request, &response);
+
+    let mut entries = cache.to_har_entries();
+    entries.sort_by(|a, b| a.url.as_str().cmp(b.url.as_str()));
+    let summary: Vec<_> = entries
+        .iter()
+        .map(|entry| (entry.url.clone(), entry.status.raw_code(), entry.body_size))
+        .collect();
+    assert_eq!(summary, [(html_url, 200, 4), (missing_url, 404, 12)]);
+    assert_eq!(entries[0].content_type.as_deref(), Some("text/html"));
+    assert!(
+        entries[0]
+            .headers
+            .contains(&("content-type".to_owned(), "text/html".to_owned()))
+    );
+    assert!(entries.iter().all(|entry| entry.body.is_none()));
+
+    let bodies: Vec<_> = cache
+        .to_har_entries_with_bodies()
+        .into_iter()
+        .filter_map(|entry| entry.body)
+        .collect();
+    assert_eq!(bodies.len(), 2);
+    assert!(bodies.contains(&b"body".to_vec()));
+}
+
+#[test]
+fn test_max_age_takes_precedence_over_expires() {
+    let needs_validation = |elapsed| {
+        store_and_lookup(
+            StatusCode::OK,
+            header_map(&[
+                (CACHE_CONTROL, "max-age=60"),
+                (EXPIRES, "Thu, 01 Jan 1970 00:00:00 GMT"),
+            ]),
+            HeaderMap::new(),
+            elapsed,
+            |_| {},
+        )
+    };
+    assert!(!needs_validation(59));
+    assert!(needs_validation(60));
+}
+
+#[test]
+fn test_max_age_takes_precedence_over_malformed_expires() {
+    let needs_validation = |elapsed| {
+        store_and_lookup(
+            StatusCode::OK,
+            header_map(&[(CACHE_CONTROL, "max-age=60"), (EXPIRES, "soon")]),
+            HeaderMap::new(),
+            elapsed,
+            |_| {},
+        )
+    };
+    assert!(!needs_validation(59));
+    assert!(needs_validation(60));
+}
+
+#[test]
+fn test_malformed_directive_does_not_hide_valid_max_age() {
+    let needs_validation = |elapsed| {
+        store_and_lookup(
+            StatusCode::OK,
+            header_map(&[
+                (CACHE_CONTROL, "max-stale=abc, max-age=60"),
+                (EXPIRES, "soon"),
+            ]),
+            HeaderMap::new(),
+            elapsed,
+            |_| {},
+        )
+    };
+    assert!(!needs_validation(59));
+    assert!(needs_validation(60));
+}
+
+#[test]
+fn test_malformed_max_age_falls_back_to_expires() {
+    let needs_validation = |expires, elapsed| {
+        store_and_lookup(
+            StatusCode::OK,
+            header_map(&[(CACHE_CONTROL, "max-age=soon"), (EXPIRES, expires)]),
+            HeaderMap::new(),
+            elapsed,
+            |_| {},
+        )
+    };
+    assert!(needs_validation("soon", 0));
+    assert!(!needs_validation("Thu, 01 Jan 2099 00:00:00 GMT", 60));
+}
+
+#[test]
+fn test_responses_with_cookies_are_stored_by_default() {
+    let is_stored = |request_headers, response_headers| {
+        store_and_construct(request_headers, response_headers, |_, _| {}).is_some()
+    };
+    assert!(is_stored(
+        header_map(&[(COOKIE, "id=1")]),
+        header_map(&[(CACHE_CONTROL, "max-age=3600")]),
+    ));
+    assert!(is_stored(
+        HeaderMap::new(),
+        header_map(&[(CACHE_CONTROL, "max-age=3600"), (SET_COOKIE, "id=1")]),
+    ));
+}
+
+#[test]
+fn test_responses_with_cookies_are_not_stored_when_opted_out() {
+    let is_stored = |request_headers, response_headers| {
+        store_and_construct(request_headers, response_headers, |cache, _| {
+            cache.set_cache_with_cookies(false)
+        })
+        .is_some()
+    };
+    assert!(!is_stored(
+        header_map(&[(COOKIE, "id=1")]),
+        header_map(&[(CACHE_CONTROL, "max-age=3600")]),
+    ));
+    assert!(!is_stored(
+        HeaderMap::new(),
+        header_map(&[(CACHE_CONTROL, "max-age=3600"), (SET_COOKIE, "id=1")]),
+    ));
+    assert!(is_stored(
+        HeaderMap::new(),
+        header_map(&[(CACHE_CONTROL, "max-age=3600")]),
+    ));
+}
+
+#[test]
+fn test_dropped_bodies_leave_validator_only_stubs() {
+    let url = ServoUrl::parse("https://servo.org/stub").unwrap();
+    let request = create_request(&url, HeaderMap::new());
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+    headers.insert(ETAG, HeaderValue::from_static("\"abc\""));
+    let response = create_response(&url, headers, ResponseBody::Done(b"body".to_vec()));
+    let mut cache = HttpCache::default();
+    cache.store(&request, &response);
+    assert!(cache.construct_response(&request, &mut None).is_some());
+
+    cache.drop_bodies_keeping_metadata();
+    assert_eq!(cache.body_bytes(), 0);
+    assert!(cache.construct_response(&request, &mut None).is_none());
+    assert_eq!(cache.explain(&request), CacheLookupExplanation::NoEntry);
+    let conditional_headers = cache
+        .conditional_headers_for(&request)
+        .expect("a stub should still provide its validators");
+    assert_eq!(conditional_headers.get(IF_NONE_MATCH).unwrap(), "\"abc\"");
+    // The response which was served keeps its body.
+    assert_eq!(
+        *response.body.lock().unwrap(),
+        ResponseBody::Done(b"body".to_vec())
+    );
+}
+
+/// Stores a response carrying an entity-tag, then drops its body, leaving a stub.
+fn cache_with_stub(url: &ServoUrl) -> (HttpCache, Request) {
+    let request = create_request(url, HeaderMap::new());
+    let response = create_response(
+        url,
+        header_map(&[(CACHE_CONTROL, "max-age=3600"), (ETAG, "\"abc\"")]),
+        ResponseBody::Done(b"body".to_vec()),
+    );
+    let mut cache = HttpCache::default();
+    cache.store(&request, &response);
+    cache.drop_bodies_keeping_metadata();
+    (cache, request)
+}
+
+#[test]
+fn test_not_modified_selecting_a_stub_removes_it() {
+    let url = ServoUrl::parse("https://servo.org/stub").unwrap();
+    let (mut cache, request) = cache_with_stub(&url);
+    let mut not_modified =
+        create_response(&url, header_map(&[(ETAG, "\"abc\"")]), ResponseBody::Empty);
+    not_modified.status = StatusCode::NOT_MODIFIED.into();
+    assert!(cache.refresh(&request, not_modified, &mut None).is_none());
+    // The resource is fetched again without validators.
+    assert!(cache.conditional_headers_for(&request).is_none());
+    assert_eq!(cache.iter_entries().count(), 0);
+}
+
+#[test]
+fn test_storing_a_resource_replaces_its_stub() {
+    let url = ServoUrl::parse("https://servo.org/stub").unwrap();
+    let (mut cache, request) = cache_with_stub(&url);
+    let response = create_response(
+        &url,
+        header_map(&[(CACHE_CONTROL, "max-age=3600"), (ETAG, "\"def\"")]),
+        ResponseBody::Done(b"fresh".to_vec()),
+    );
+    cache.store(&request, &response);
+    assert_eq!(cache.iter_entries().count(), 1);
+    let cached = cache.construct_response(&request, &mut None).unwrap();
+    assert_eq!(
+        *cached.response.body.lock().unwrap(),
+        ResponseBody::Done(b"fresh".to_vec())
+    );
+}
+
+#[test]
+fn test_prune_expired_removes_stubs() {
+    let url = ServoUrl::parse("https://servo.org/stub").unwrap();
+    let (mut cache, request) = cache_with_stub(&url);
+    assert_eq!(cache.prune_expired(), 1);
+    assert!(cache.conditional_headers_for(&request).is_none());
+}
+
+#[test]
+fn test_pragma_no_cache_alone_prevents_storing() {
+    assert!(
+        store_and_construct(
+            HeaderMap::new(),
+            header_map(&[
+                (PRAGMA, "no-cache"),
+                (LAST_MODIFIED, "Wed, 21 Oct 2015 07:28:00 GMT"),
+            ]),
+            |_, _| {},
+        )
+        .is_none()
+    );
+}
+
+#[test]
+fn test_pragma_no_cache_is_ignored_with_cache_control() {
+    assert!(
+        store_and_construct(
+            HeaderMap::new(),
+            header_map(&[
+                (PRAGMA, "no-cache"),
+                (LAST_MODIFIED, "Wed, 21 Oct 2015 07:28:00 GMT"),
+                (CACHE_CONTROL, "max-age=60"),
+            ]),
+            |_, _| {},
+        )
+        .is_some()
+    );
+}
+
+#[test]
+fn test_cache_control_override_clamps_long_max_age() {
+    let cache_control_override = CacheControlOverride {
+        min_freshness: None,
+        max_freshness: Some(Duration::from_secs(60)),
+    };
+    let needs_validation = |elapsed| {
+        store_and_lookup(
+            StatusCode::OK,
+            header_map(&[(CACHE_CONTROL, "max-age=3600")]),
+            HeaderMap::new(),
+            elapsed,
+            |cache| cache.set_cache_control_override(Some(cache_control_override)),
+        )
+    };
+    assert!(!needs_validation(59));
+    assert!(needs_validation(60));
+}
+
+#[test]
+fn test_cache_control_override_extends_zero_max_age() {
+    let cache_control_override = CacheControlOverride {
+        min_freshness: Some(Duration::from_secs(60)),
+        max_freshness: None,
+    };
+    let needs_validation = |elapsed| {
+        store_and_lookup(
+            StatusCode::OK,
+            header_map(&[(CACHE_CONTROL, "max-age=0")]),
+            HeaderMap::new(),
+            elapsed,
+            |cache| cache.set_cache_control_override(Some(cache_control_override)),
+        )
+    };
+    assert!(!needs_validation(59));
+    assert!(needs_validation(60));
+}
+
+#[test]
+fn test_repeated_range_is_served_from_range_index() {
+    let url = ServoUrl::parse("https://servo.org/video.webm").unwrap();
+    let mut cache = HttpCache::default();
+    cache.set_range_index_capacity(4);
+    store_body(&mut cache, &url, b"0123456789".to_vec());
+
+    for _ in 0..2 {
+        let partial_response = range_response(&cache, &url, "bytes=2-5").unwrap();
+        assert_eq!(partial_response.status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            *partial_response.body.lock().unwrap(),
+            ResponseBody::Done(b"2345".to_vec())
+        );
+    }
+    assert_eq!(cache.range_index_hits(), 1);
+
+    // Another range is sliced from the stored body.
+    let partial_response = range_response(&cache, &url, "bytes=6-").unwrap();
+    assert_eq!(
+        *partial_response.body.lock().unwrap(),
+        ResponseBody::Done(b"6789".to_vec())
+    );
+    assert_eq!(cache.range_index_hits(), 1);
+}
+
+#[test]
+fn test_range_index_counts_towards_memory_budget() {
+    let url = ServoUrl::parse("https://servo.org/track.webm").unwrap();
+    let other_url = ServoUrl::parse("https://servo.org/other.webm").unwrap();
+    let mut cache = HttpCache::default();
+    cache.set_range_index_capacity(4);
+    cache.set_memory_budget(Some(20));
+    store_body(&mut cache, &url, b"0123456789".to_vec());
+    assert!(range_response(&cache, &url, "bytes=0-7").is_some());
+
+    // The indexed range is dropped, rather than a stored body, to fit in the budget.
+    store_body(&mut cache, &other_url, b"0123456789".to_vec());
+    assert!(is_cached(&cache, &url));
+    assert!(is_cached(&cache, &other_url));
+    assert!(range_response(&cache, &url, "bytes=0-7").is_some());
+    assert_eq!(cache.range_index_hits(), 0);
+}
+
+#[test]
+fn test_range_index_is_disabled_by_default() {
+    let url = ServoUrl::parse("https://servo.org/audio.ogg").unwrap();
+    let mut cache = HttpCache::default();
+    store_body(&mut cache, &url, b"0123456789".to_vec());
+
+    for _ in 0..2 {
+        assert!(range_response(&cache, &url, "bytes=2-5").is_some());
+    }
+    assert_eq!(cache.range_index_hits(), 0);
+}
+
+fn lookup_in_cache_mode(cache_mode: CacheMode) -> Option<bool> {
+    let url = ServoUrl::parse("https://servo.org/cache-mode").unwrap();
+    let mut cache = HttpCache::default();
+    store_body(&mut cache, &url, b"body".to_vec());
+    let mut request = create_request(&url, HeaderMap::new());
+    request.cache_mode = cache_mode;
+    cache
+        .construct_response(&request, &mut None)
+        .map(|cached_response| cached_response.needs_validation)
+}
+
+#[test]
+fn test_cache_mode_selects_whether_the_cache_is_consulted() {
+    assert_eq!(lookup_in_cache_mode(CacheMode::Default), Some(false));
+    assert_eq!(lookup_in_cache_mode(CacheMode::NoStore), None);
+    assert_eq!(lookup_in_cache_mode(CacheMode::Reload), None);
+    assert_eq!(lookup_in_cache_mode(CacheMode::NoCache), Some(true));
+    assert_eq!(lookup_in_cache_mode(CacheMode::ForceCache), Some(false));
+    assert_eq!(lookup_in_cache_mode(CacheMode::OnlyIfCached), Some(false));
+}
+
+#[test]
+fn test_cache_mode_selects_whether_the_cache_is_updated() {
+    // The resource is looked up with the default cache mode.
+    let is_stored_in_cache_mode = |cache_mode| {
+        store_and_construct(
+            HeaderMap::new(),
+            header_map(&[(CACHE_CONTROL, "max-age=3600")]),
+            |_, request| request.cache_mode = cache_mode,
+        )
+        .is_some()
+    };
+    assert!(is_stored_in_cache_mode(CacheMode::Default));
+    assert!(!is_stored_in_cache_mode(CacheMode::NoStore));
+    assert!(is_stored_in_cache_mode(CacheMode::Reload));
+    assert!(is_stored_in_cache_mode(CacheMode::NoCache));
+    assert!(is_stored_in_cache_mode(CacheMode::ForceCache));
+    assert!(is_stored_in_cache_mode(CacheMode::OnlyIfCached));
+}
+
+#[test]
+fn test_explain_cache_mode_bypass() {
+    let url = ServoUrl::parse("https://servo.org/explained-reload").unwrap();
+    let mut cache = HttpCache::default();
+    store_body(&mut cache, &url, b"body".to_vec());
+    let mut request = create_request(&url, HeaderMap::new());
+    request.cache_mode = CacheMode::Reload;
+
+    assert_eq!(
+        cache.explain(&request),
+        CacheLookupExplanation::BypassedByCacheMode
+    );
+}
+
+#[test]
+fn test_date_is_generated_when_missing() {
+    let url = ServoUrl::parse("https://servo.org/undated").unwrap();
+    let before = SystemTime::now() - Duration::from_secs(1);
+    let mut cache = HttpCache::default();
+    store_body(&mut cache, &url, b"body".to_vec());
+    let after = SystemTime::now() + Duration::from_secs(1);
+
+    let request = create_request(&url, HeaderMap::new());
+    let cached_response = cache.construct_response(&request, &mut None).unwrap();
+    let date: SystemTime = cached_response
+        .response
+        .headers
+        .typed_get::<Date>()
+        .expect("a Date header should be generated")
+        .into();
+    assert!(before <= date && date <= after);
+}
+
+#[test]
+fn test_date_from_origin_is_kept() {
+    let url = ServoUrl::parse("https://servo.org/dated").unwrap();
+    let request = create_request(&url, HeaderMap::new());
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+    headers.insert(
+        DATE,
+        HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"),
+    );
+    let response = create_response(&url, headers, ResponseBody::Done(b"body".to_vec()));
+    let mut cache = HttpCache::default();
+    cache.store(&request, &response);
+
+    let cached_response = cache.construct_response(&request, &mut None).unwrap();
+    assert_eq!(
+        cached_response.response.headers.get(DATE).unwrap(),
+        "Wed, 21 Oct 2015 07:28:00 GMT"
+    );
+}
+
+fn await_language_variant(cache: &mut HttpCache, url: &ServoUrl) -> Receiver<Data> {
+    let mut request_headers = HeaderMap::new();
+    request_headers.insert(ACCEPT_LANGUAGE, HeaderValue::from_static("en"));
+    let request = create_request(url, request_headers);
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+    headers.insert(VARY, HeaderValue::from_static("Accept-Language"));
+    let response = create_response(url, headers, ResponseBody::Receiving(vec![]));
+    cache.store(&request, &response);
+
+    let (send, recv) = unbounded();
+    let mut done_chan = Some((send, recv));
+    assert!(cache.construct_response(&request, &mut done_chan).is_some());
+    done_chan.take().unwrap().1
+}
+
+#[test]
+fn test_evicted_variant_notifies_awaiting_consumers() {
+    let url = ServoUrl::parse("https://servo.org/greeting").unwrap();
+    let mut cache = HttpCache::default();
+    cache.set_max_variants_per_key(1);
+    let mut receiver = await_language_variant(&mut cache, &url);
+
+    let mut request_headers = HeaderMap::new();
+    request_headers.insert(ACCEPT_LANGUAGE, HeaderValue::from_static("fr"));
+    let request = create_request(&url, request_headers);
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+    headers.insert(VARY, HeaderValue::from_static("Accept-Language"));
+    let response = create_response(&url, headers, ResponseBody::Done(b"bonjour".to_vec()));
+    cache.store(&request, &response);
+
+    assert!(matches!(receiver.try_recv(), Ok(Data::Cancelled)));
+}
+
+#[test]
+fn test_clear_notifies_awaiting_consumers() {
+    let url = ServoUrl::parse("https://servo.org/greeting").unwrap();
+    let mut cache = HttpCache::default();
+    let mut receiver = await_language_variant(&mut cache, &url);
+
+    cache.clear();
+
+    assert!(matches!(receiver.try_recv(), Ok(Data::Cancelled)));
+}
+
+fn lookup_with_no_cache(cache_control: &'static str) -> Option<CachedResponse> {
+    let url = ServoUrl::parse("https://servo.org/no-cache-only").unwrap();
+    let request = create_request(&url, HeaderMap::new());
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static(cache_control));
+    headers.insert(SET_COOKIE, HeaderValue::from_static("session=1"));
+    let response = create_response(&url, headers, ResponseBody::Done(b"body".to_vec()));
+    let mut cache = HttpCache::default();
+    cache.store(&request, &response);
+    cache.construct_response(&request, &mut None)
+}
+
+#[test]
+fn test_unqualified_no_cache_alone_is_stored_for_revalidation() {
+    let cached = lookup_with_no_cache("no-cache").unwrap();
+    assert!(cached.needs_validation);
+}
+
+#[test]
+fn test_qualified_no_cache_alone_is_stored_without_named_fields() {
+    let cached = lookup_with_no_cache("no-cache=\"Set-Cookie\"").unwrap();
+    assert!(!cached.needs_validation);
+    assert!(!cached.response.headers.contains_key(SET_COOKIE));
+}
+
+#[cfg(feature = "lock-contention")]
+#[test]
+fn test_contended_resource_shows_up_in_contention_report() {
+    let url = ServoUrl::parse("https://servo.org/hot").unwrap();
+    let cold_url = ServoUrl::parse("https://servo.org/cold").unwrap();
+    let request = create_request(&url, HeaderMap::new());
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+    let response = create_response(&url, headers, ResponseBody::Done(b"body".to_vec()));
+    let cache = Arc::new(RwLock::new(HttpCache::default()));
+    cache.write().unwrap().store(&request, &response);
+    store_body(&mut cache.write().unwrap(), &cold_url, b"body".to_vec());
+
+    // The body of the stored resource is shared with the response, hold it while looking up.
+    let body = response.body.lock().unwrap();
+    let lookup = {
+        let cache = cache.clone();
+        thread::spawn(move || {
+            let cache = cache.read().unwrap();
+            assert!(cache.construct_response(&request, &mut None).is_some());
+        })
+    };
+    thread::sleep(Duration::from_millis(50));
+    drop(body);
+    lookup.join().unwrap();
+
+    let report = cache.read().unwrap().contention_report();
+    assert_eq!(report.len(), 1);
+    assert_eq!(report[0].url, url);
+    assert!(report[0].contended >= 1);
+    assert!(report[0].waited > Duration::ZERO);
+}
+
+#[test]
+fn test_cached_response_carries_stored_content_type_and_charset() {
+    let url = ServoUrl::parse("https://servo.org/typed").unwrap();
+    let mut cache = HttpCache::default();
+    store_with_content_type(&mut cache, &url, "text/html; charset=utf-8");
+
+    let request = create_request(&url, HeaderMap::new());
+    let cached_response = cache.construct_response(&request, &mut None).unwrap();
+    let content_type = cached_response.content_type.unwrap();
+    assert_eq!(content_type.essence_str(), "text/html");
+    assert_eq!(cached_response.charset.as_deref(), Some("utf-8"));
+    let info = cache.get_by_url(&url).unwrap();
+    assert_eq!(info.content_type, Some(content_type.to_string()));
+}
+
+#[test]
+fn test_revalidation_lists_and_selects_variant_entity_tags() {
+    let url = ServoUrl::parse("https://servo.org/tagged-variants").unwrap();
+    let mut cache = HttpCache::default();
+    let mut requests = vec![];
+    for (encoding, entity_tag) in [("gzip", "\"g\""), ("br", "\"b\"")] {
+        let mut request_headers = HeaderMap::new();
+        request_headers.insert(ACCEPT_ENCODING, HeaderValue::from_static(encoding));
+        let request = create_request(&url, request_headers);
+        let mut headers = HeaderMap::new();
+        headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=0"));
+        headers.insert(VARY, HeaderValue::from_static("accept-encoding"));
+        headers.insert(ETAG, HeaderValue::from_static(entity_tag));
+        let response = create_response(&url, headers, ResponseBody::Done(encoding.into()));
+        cache.store(&request, &response);
+        requests.push(request);
+    }
+    let (gzip_request, br_request) = (&requests[0], &requests[1]);
+
+    let conditional_headers = cache.conditional_headers_for(gzip_request).unwrap();
+    assert_eq!(
+        conditional_headers.get(IF_NONE_MATCH).unwrap(),
+        "\"g\", \"b\""
+    );
+
+    // The server picks the br variant.
+    let mut not_modified_headers = HeaderMap::new();
+    not_modified_headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+    not_modified_headers.insert(ETAG, HeaderValue::from_static("\"b\""));
+    let mut not_modified = create_response(&url, not_modified_headers, ResponseBody::Empty);
+    not_modified.status = StatusCode::NOT_MODIFIED.into();
+    let refreshed = cache
+        .refresh(gzip_request, not_modified, &mut None)
+        .expect("the variant named by the 304 response should be refreshed");
+    assert_eq!(
+        *refreshed.body.lock().unwrap(),
+        ResponseBody::Done(b"br".to_vec())
+    );
+
+    let br_response = cache.construct_response(br_request, &mut None).unwrap();
+    assert!(!br_response.needs_validation);
+    let gzip_response = cache.construct_response(gzip_request, &mut None).unwrap();
+    assert!(gzip_response.needs_validation);
+}
+
+#[test]
+fn test_expires_zero_is_already_stale() {
+    assert!(store_and_lookup(
+        StatusCode::OK,
+        header_map(&[(EXPIRES, "0")]),
+        HeaderMap::new(),
+        0,
+        |_| {},
+    ));
+}
+
+#[test]
+fn test_expires_in_1970_is_already_stale() {
+    assert!(store_and_lookup(
+        StatusCode::OK,
+        header_map(&[(EXPIRES, "Thu, 01 Jan 1970 00:00:00 GMT")]),
+        HeaderMap::new(),
+        0,
+        |_| {},
+    ));
+}
+
+#[test]
+fn test_expires_equal_to_date_is_already_stale() {
+    let date = "Wed, 21 Oct 2015 07:28:00 GMT";
+    assert!(store_and_lookup(
+        StatusCode::OK,
+        header_map(&[(DATE, date), (EXPIRES, date)]),
+        HeaderMap::new(),
+        0,
+        |_| {},
+    ));
+}
+
+#[test]
+fn test_body_bytes_for_returns_the_stored_complete_body() {
+    let url = ServoUrl::parse("https://servo.org/stored").unwrap();
+    let partial_url = ServoUrl::parse("https://servo.org/stored-partial").unwrap();
+    let mut cache = HttpCache::default();
+    store_body(&mut cache, &url, b"complete body".to_vec());
+    store_partial(&mut cache, &partial_url, "bytes 0-3/10", b"part");
+
+    assert_eq!(cache.body_bytes_for(&url), Some(b"complete body".to_vec()));
+    assert!(cache.get_by_url(&partial_url).is_some());
+    assert_eq!(cache.body_bytes_for(&partial_url), None);
+}
+
+fn is_authorized_response_stored(mode: HttpCacheMode, cache_control: &'static str) -> bool {
+    store_and_construct(
+        header_map(&[(AUTHORIZATION, "Basic dXNlcg==")]),
+        header_map(&[(CACHE_CONTROL, cache_control)]),
+        |cache, _| cache.set_mode(mode),
+    )
+    .is_some()
+}
+
+#[test]
+fn test_authorized_response_is_stored_in_private_mode() {
+    assert!(is_authorized_response_stored(
+        HttpCacheMode::Private,
+        "max-age=3600"
+    ));
+}
+
+#[test]
+fn test_authorized_response_needs_public_in_shared_mode() {
+    assert!(!is_authorized_response_stored(
+        HttpCacheMode::Shared,
+        "max-age=3600"
+    ));
+    assert!(is_authorized_response_stored(
+        HttpCacheMode::Shared,
+        "public, max-age=3600"
+    ));
+}
+
+#[test]
+fn test_clear_site_data_purges_the_origin() {
+    let urls = [
+        ServoUrl::parse("https://servo.org/index.html").unwrap(),
+        ServoUrl::parse("https://servo.org/style.css").unwrap(),
+        ServoUrl::parse("https://example.com/index.html").unwrap(),
+    ];
+    let mut cache = HttpCache::default();
+    for url in &urls {
+        store_body(&mut cache, url, b"body".to_vec());
+    }
+
+    let logout_url = ServoUrl::parse("https://servo.org/logout").unwrap();
+    let request = create_request(&logout_url, HeaderMap::new());
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        HeaderName::from_static("clear-site-data"),
+        HeaderValue::from_static("\"cookies\", \"cache\""),
+    );
+    let response = create_response(&logout_url, headers, ResponseBody::Empty);
+    cache.apply_clear_site_data(&request, &response);
+
+    assert!(!is_cached(&cache, &urls[0]));
+    assert!(!is_cached(&cache, &urls[1]));
+    assert!(is_cached(&cache, &urls[2]));
+}
+
+#[test]
+fn test_clear_site_data_without_cache_type_keeps_the_origin() {
+    let url = ServoUrl::parse("https://servo.org/index.html").unwrap();
+    let mut cache = HttpCache::default();
+    store_body(&mut cache, &url, b"body".to_vec());
+
+    let request = create_request(&url, HeaderMap::new());
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        HeaderName::from_static("clear-site-data"),
+        HeaderValue::from_static("\"cookies\""),
+    );
+    let response = create_response(&url, headers, ResponseBody::Empty);
+    cache.apply_clear_site_data(&request, &response);
+
+    assert!(is_cached(&cache, &url));
+}
+
+fn stored_set_cookie_in_mode(mode: HttpCacheMode) -> Option<HeaderValue> {
+    let cached = store_and_construct(
+        HeaderMap::new(),
+        header_map(&[(CACHE_CONTROL, "max-age=3600"), (SET_COOKIE, "session=1")]),
+        |cache, _| cache.set_mode(mode),
+    )
+    .unwrap();
+    assert_eq!(
+        *cached.response.body.lock().unwrap(),
+        ResponseBody::Done(b"body".to_vec())
+    );
+    cached.response.headers.get(SET_COOKIE).cloned()
+}
+
+#[test]
+fn test_shared_mode_does_not_store_set_cookie() {
+    assert_eq!(stored_set_cookie_in_mode(HttpCacheMode::Shared), None);
+}
+
+#[test]
+fn test_private_mode_stores_set_cookie() {
+    assert_eq!(
+        stored_set_cookie_in_mode(HttpCacheMode::Private),
+        Some(HeaderValue::from_static("session=1"))
+    );
+}
+
+#[test]
+fn test_request_max_age_forces_validation_of_range_response() {
+    let url = ServoUrl::parse("https://servo.org/fresh.bin").unwrap();
+    let mut cache = HttpCache::default();
+    store_body(&mut cache, &url, vec![0; 16]);
+
+    let mut headers = HeaderMap::new();
+    headers.insert(RANGE, HeaderValue::from_static("bytes=0-3"));
+    let request = create_request(&url, headers.clone());
+    let cached = cache.construct_response(&request, &mut None).unwrap();
+    assert_eq!(cached.response.status, StatusCode::PARTIAL_CONTENT);
+    assert!(!cached.needs_validation);
+
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=0"));
+    let request = create_request(&url, headers);
+    let cached = cache.construct_response(&request, &mut None).unwrap();
+    assert_eq!(cached.response.status, StatusCode::PARTIAL_CONTENT);
+    assert!(cached.needs_validation);
+}
+
+#[test]
+fn test_variants_for_reports_the_selecting_request_headers() {
+    let url = ServoUrl::parse("https://servo.org/compressed.js").unwrap();
+    let mut cache = HttpCache::default();
+    for encoding in ["gzip", "br"] {
+        let mut request_headers = HeaderMap::new();
+        request_headers.insert(ACCEPT_ENCODING, HeaderValue::from_static(encoding));
+        let request = create_request(&url, request_headers);
+        let mut headers = HeaderMap::new();
+        headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+        headers.insert(VARY, HeaderValue::from_static("Accept-Encoding"));
+        let response = create_response(&url, headers, ResponseBody::Done(b"body".to_vec()));
+        cache.store(&request, &response);
+    }
+
+    let variants: Vec<VariantInfo> = cache.variants_for(&url);
+    assert_eq!(variants.len(), 2);
+    for (variant, encoding) in variants.iter().zip(["gzip", "br"]) {
+        assert_eq!(variant.status, StatusCode::OK);
+        assert_eq!(variant.vary.as_deref(), Some("Accept-Encoding"));
+        assert_eq!(
+            variant.selected_request_headers,
+            vec![("accept-encoding".to_owned(), Some(encoding.to_owned()))]
+        );
+    }
+    assert!(
+        cache
+            .variants_for(&ServoUrl::parse("https://servo.org/other.js").unwrap())
+            .is_empty()
+    );
+}
+
+/// Stores a fresh resource for the url, then poisons the mutex of its body.
+fn cache_with_poisoned_resource(url: &ServoUrl) -> (HttpCache, Request) {
+    let request = create_request(url, HeaderMap::new());
+    let response = create_response(
+        url,
+        header_map(&[(CACHE_CONTROL, "max-age=3600")]),
+        ResponseBody::Done(b"body".to_vec()),
+    );
+    let mut cache = HttpCache::default();
+    cache.store(&request, &response);
+    assert!(cache.construct_response(&request, &mut None).is_some());
+
+    // The stored body is shared with the response it was stored from.
+    let body = response.body.clone();
+    let _ = thread::spawn(move || {
+        let _guard = body.lock().unwrap();
+        panic!("poisoning the body of a stored resource");
+    })
+    .join();
+    assert!(response.body.is_poisoned());
+    (cache, request)
+}
+
+#[test]
+fn test_poisoned_resource_is_treated_as_absent() {
+    let url = ServoUrl::parse("https://servo.org/poisoned.html").unwrap();
+    let (cache, request) = cache_with_poisoned_resource(&url);
+
+    assert!(cache.construct_response(&request, &mut None).is_none());
+    assert!(cache.freshness_remaining(&request).is_none());
+    assert!(cache.conditional_headers_for(&request).is_none());
+    assert!(cache.get_by_url(&url).is_none());
+    assert!(cache.body_bytes_for(&url).is_none());
+    assert!(cache.variants_for(&url).is_empty());
+    assert_eq!(cache.iter_entries().count(), 0);
+}
+
+#[test]
+fn test_poisoned_resource_is_evicted() {
+    let url = ServoUrl::parse("https://servo.org/poisoned.html").unwrap();
+    let (mut cache, _) = cache_with_poisoned_resource(&url);
+    let evictions = Arc::new(Mutex::new(vec![]));
+    let recorded_evictions = evictions.clone();
+    cache.set_on_evict(Some(Box::new(move |url, _| {
+        recorded_evictions.lock().unwrap().push(url.clone());
+    })));
+
+    assert_eq!(cache.prune_expired(), 1);
+    assert_eq!(*evictions.lock().unwrap(), vec![url]);
+}
+
+#[test]
+fn test_storing_a_resource_replaces_a_poisoned_one() {
+    let url = ServoUrl::parse("https://servo.org/poisoned.html").unwrap();
+    let (mut cache, request) = cache_with_poisoned_resource(&url);
+    let response = create_response(
+        &url,
+        header_map(&[(CACHE_CONTROL, "max-age=3600")]),
+        ResponseBody::Done(b"fresh".to_vec()),
+    );
+    cache.store(&request, &response);
+
+    assert_eq!(cache.body_bytes_for(&url), Some(b"fresh".to_vec()));
+    assert!(cache.construct_response(&request, &mut None).is_some());
+}
+
+fn vary_origin_hits(filter_type: Option<ResponseType>) -> (bool, bool) {
+    let url = ServoUrl::parse("https://cdn.servo.org/font.woff2").unwrap();
+    let request_from = |origin: &'static str| {
+        let mut headers = HeaderMap::new();
+        headers.insert(ORIGIN, HeaderValue::from_static(origin));
+        create_request(&url, headers)
+    };
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+    headers.insert(VARY, HeaderValue::from_static("Origin"));
+    let mut response = create_response(&url, headers, ResponseBody::Done(b"font".to_vec()));
+    if let Some(filter_type) = filter_type {
+        response = response.to_filtered(filter_type);
+    }
+    let mut cache = HttpCache::default();
+    cache.store(&request_from("https://a.example"), &response);
+
+    let hits = |origin| {
+        cache
+            .construct_response(&request_from(origin), &mut None)
+            .is_some()
+    };
+    (hits("https://a.example"), hits("https://b.example"))
+}
+
+#[test]
+fn test_vary_origin_misses_for_another_origin() {
+    assert_eq!(vary_origin_hits(None), (true, false));
+}
+
+#[test]
+fn test_vary_origin_of_cors_response_misses_for_another_origin() {
+    // The Vary header isn't exposed by the CORS filtered response.
+    assert_eq!(vary_origin_hits(Some(ResponseType::Cors)), (true, false));
+}